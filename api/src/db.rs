@@ -1,40 +1,140 @@
 use crate::{
-    auth::UserId, error::ApiResult, graphql::Tag, spotify::SpotifyUri,
+    auth::UserId,
+    error::{ApiError, ApiResult},
+    graphql::{
+        RatingFilter, SmartTagCondition, Tag, TagAction, TagFilter, TagRules,
+        TAG_SEPARATOR,
+    },
+    provider::{ItemType, ItemUri},
     LauludConfig,
 };
 use derive_more::{Deref, From};
-use futures::StreamExt;
+use futures::{StreamExt, TryStreamExt};
+use itertools::Itertools;
 use mongodb::{
-    bson::{self, doc, Document},
-    options::ClientOptions,
-    Client, Collection, Cursor, Database,
+    bson::{self, doc, oid::ObjectId, Bson, Document},
+    options::{
+        AggregateOptions, ClientOptions, FindOneAndUpdateOptions, FindOptions,
+        IndexOptions, ReplaceOptions, ReturnDocument, UpdateOptions,
+    },
+    Client, Collection, Cursor, Database, IndexModel,
 };
 use serde::{Deserialize, Serialize};
+use std::{
+    backtrace::Backtrace,
+    collections::HashMap,
+    iter,
+    time::{Duration, SystemTime},
+};
 
 const DATABASE_NAME: &str = "laulud";
+/// The current version of the DB schema. Bump this whenever a migration is
+/// added to [DbHandler::run_migrations].
+const SCHEMA_VERSION: u32 = 1;
 
 pub struct DbHandler {
     client: Client,
+    /// How long tag mutations can be undone for
+    undo_expiry: Duration,
 }
 
 impl DbHandler {
     pub async fn connect(config: &LauludConfig) -> ApiResult<Self> {
         let options = ClientOptions::parse(&config.database_url).await?;
         let client = Client::with_options(options).unwrap();
-        Ok(Self { client })
+        Ok(Self {
+            client,
+            undo_expiry: Duration::from_secs(config.undo_expiry_seconds),
+        })
     }
 
     fn database(&self) -> Database {
         self.client.database(DATABASE_NAME)
     }
 
+    /// Bring existing data up to date with the current DB schema. We keep
+    /// track of which migrations have already run, so each one only has to
+    /// scan the DB once, rather than on every startup. Tags are normalized
+    /// again whenever the configured [TagRules] change. Indexes are (re)created
+    /// every time, since that's a no-op when they already exist.
+    pub async fn run_migrations(&self) -> ApiResult<()> {
+        self.collection_undo_entries().create_indexes().await?;
+
+        let migrations = self.collection_migrations();
+        let mut state = migrations.find_state().await?;
+
+        if state.schema_version < 1 {
+            self.collection_tagged_items().backfill_timestamps().await?;
+        }
+
+        // This relies on timestamps already being backfilled
+        let tag_rules = TagRules::get();
+        if state.tag_rules.as_ref() != Some(tag_rules) {
+            self.collection_tagged_items().normalize_tags().await?;
+            self.collection_tags().normalize_tags().await?;
+            self.collection_tag_events().normalize_tags().await?;
+            self.collection_smart_tags().normalize_tags().await?;
+        }
+
+        state.schema_version = SCHEMA_VERSION;
+        state.tag_rules = Some(tag_rules.clone());
+        migrations.save_state(&state).await
+    }
+
     /// Get a reference to the `taggedItems` collection from the DB. This can
     /// be used for any and all interactions with that collection. See the
     /// [TaggedItemsCollection] wrapper type for additional functionality
     /// provided beyond the stock Mongo functions.
     pub fn collection_tagged_items(&self) -> TaggedItemsCollection {
+        TaggedItemsCollection::new(self.database())
+    }
+
+    /// Get a reference to the `tags` collection from the DB. See the
+    /// [TagsCollection] wrapper type for additional functionality provided
+    /// beyond the stock Mongo functions.
+    pub fn collection_tags(&self) -> TagsCollection {
+        self.database().collection(TagsCollection::name()).into()
+    }
+
+    /// Get a reference to the `undoEntries` collection from the DB. See the
+    /// [UndoEntriesCollection] wrapper type for additional functionality
+    /// provided beyond the stock Mongo functions.
+    pub fn collection_undo_entries(&self) -> UndoEntriesCollection {
+        UndoEntriesCollection {
+            collection: self
+                .database()
+                .collection(UndoEntriesCollection::name()),
+            expiry: self.undo_expiry,
+        }
+    }
+
+    /// Get a reference to the `tagEvents` collection from the DB, which holds
+    /// the audit log of tag changes. See the [TagEventsCollection] wrapper
+    /// type for additional functionality provided beyond the stock Mongo
+    /// functions.
+    pub fn collection_tag_events(&self) -> TagEventsCollection {
+        self.database()
+            .collection(TagEventsCollection::name())
+            .into()
+    }
+
+    /// Get a reference to the `smartTags` collection from the DB, which holds
+    /// rule definitions for smart tags. See the [SmartTagsCollection] wrapper
+    /// type for additional functionality provided beyond the stock Mongo
+    /// functions.
+    pub fn collection_smart_tags(&self) -> SmartTagsCollection {
+        self.database()
+            .collection(SmartTagsCollection::name())
+            .into()
+    }
+
+    /// Get a reference to the `migrations` collection from the DB, which
+    /// tracks which migrations have been run. See the [MigrationsCollection]
+    /// wrapper type for additional functionality provided beyond the stock
+    /// Mongo functions.
+    pub fn collection_migrations(&self) -> MigrationsCollection {
         self.database()
-            .collection(TaggedItemsCollection::name())
+            .collection(MigrationsCollection::name())
             .into()
     }
 }
@@ -50,40 +150,158 @@ impl DbHandler {
 /// Right now the app doesn't support any cross-user interaction, so every
 /// method on this filters by user ID. As such, we don't bother mentioning
 /// the user in the method name, for brevity.
-#[derive(Debug, Deref, From)]
+#[derive(Debug, Deref)]
 pub struct TaggedItemsCollection {
+    #[deref]
     collection: Collection<TaggedItemDocument>,
+    /// Some operations (e.g. bulk writes) aren't supported by the driver at
+    /// the collection level, so we need the DB to run raw commands
+    database: Database,
 }
 
 impl TaggedItemsCollection {
+    fn new(database: Database) -> Self {
+        Self {
+            collection: database.collection(Self::name()),
+            database,
+        }
+    }
+
     // Get the name of this collection, as defined in the DB
     pub fn name() -> &'static str {
         "taggedItems"
     }
 
+    /// Get the documents for a group of items that are owned by a particular
+    /// user. Items that the user has never tagged won't have a document, so
+    /// the output may be shorter than the input.
+    pub async fn find_by_items(
+        &self,
+        user_id: &UserId,
+        item_uris: &[ItemUri],
+    ) -> ApiResult<Cursor<TaggedItemDocument>> {
+        let item_uris: Vec<Bson> = item_uris.iter().map(Bson::from).collect();
+        Ok(self
+            .collection
+            .find(doc! {"user_id": user_id, "uri": {"$in": item_uris}}, None)
+            .await?)
+    }
+
+    /// Get the document for a single item owned by a particular user. Returns
+    /// `None` if the user has never tagged the item.
+    pub async fn find_by_item(
+        &self,
+        user_id: &UserId,
+        item_uri: &ItemUri,
+    ) -> ApiResult<Option<TaggedItemDocument>> {
+        Ok(self
+            .collection
+            .find_one(Self::filter_by_item(user_id, item_uri), None)
+            .await?)
+    }
+
     /// Filter this collection for documents owned by a particular user that
-    /// have a particular tag applied
+    /// have a particular tag applied. If `include_descendants` is set, items
+    /// with any tag nested under the given one will match too. If `rating` is
+    /// given, only items rated within that range will match. Items in
+    /// `smart_uris` match regardless of their stored tags, which is how smart
+    /// tag matches get included.
     pub async fn find_by_tag(
         &self,
         user_id: &UserId,
         tag: &Tag,
+        include_descendants: bool,
+        rating: Option<&RatingFilter>,
+        smart_uris: &[ItemUri],
     ) -> ApiResult<Cursor<TaggedItemDocument>> {
         Ok(self
             .collection
-            .find(Self::filter_by_tag(user_id, tag), None)
+            .find(
+                Self::filter_by_tag_tree(
+                    user_id,
+                    tag,
+                    include_descendants,
+                    rating,
+                    smart_uris,
+                ),
+                None,
+            )
             .await?)
     }
 
     /// Count the number of documents owned by a particular user that
-    /// have a particular tag applied
+    /// have a particular tag applied. If `include_descendants` is set, items
+    /// with any tag nested under the given one will be counted too. If
+    /// `rating` is given, only items rated within that range will be counted.
+    /// Items in `smart_uris` are counted regardless of their stored tags.
     pub async fn count_by_tag(
         &self,
         user_id: &UserId,
         tag: &Tag,
+        include_descendants: bool,
+        rating: Option<&RatingFilter>,
+        smart_uris: &[ItemUri],
+    ) -> ApiResult<u64> {
+        Ok(self
+            .collection
+            .count_documents(
+                Self::filter_by_tag_tree(
+                    user_id,
+                    tag,
+                    include_descendants,
+                    rating,
+                    smart_uris,
+                ),
+                None,
+            )
+            .await?)
+    }
+
+    /// Get up to `limit` of a user's documents, most recently updated first.
+    /// Documents that have never been updated (e.g. ones that only have a
+    /// note) come last.
+    pub async fn find_recent(
+        &self,
+        user_id: &UserId,
+        limit: i64,
+    ) -> ApiResult<Cursor<TaggedItemDocument>> {
+        Ok(self
+            .collection
+            .find(
+                Self::filter_by_user(user_id),
+                Some(
+                    FindOptions::builder()
+                        .sort(doc! {"updated_at": -1, "_id": -1})
+                        .limit(limit)
+                        .build(),
+                ),
+            )
+            .await?)
+    }
+
+    /// Filter this collection for documents owned by a particular user whose
+    /// tags match a boolean filter expression
+    pub async fn find_by_filter(
+        &self,
+        user_id: &UserId,
+        filter: &TagFilter,
+    ) -> ApiResult<Cursor<TaggedItemDocument>> {
+        Ok(self
+            .collection
+            .find(Self::filter_by_expression(user_id, filter), None)
+            .await?)
+    }
+
+    /// Count the number of documents owned by a particular user whose tags
+    /// match a boolean filter expression
+    pub async fn count_by_filter(
+        &self,
+        user_id: &UserId,
+        filter: &TagFilter,
     ) -> ApiResult<u64> {
         Ok(self
             .collection
-            .count_documents(Self::filter_by_tag(user_id, tag), None)
+            .count_documents(Self::filter_by_expression(user_id, filter), None)
             .await?)
     }
 
@@ -97,7 +315,7 @@ impl TaggedItemsCollection {
     pub async fn count_tags_by_item(
         &self,
         user_id: &UserId,
-        item_uri: &SpotifyUri,
+        item_uri: &ItemUri,
     ) -> ApiResult<u64> {
         self.count_tags_helper(Self::filter_by_item(user_id, item_uri))
             .await
@@ -113,113 +331,1849 @@ impl TaggedItemsCollection {
     pub async fn find_tags_by_item(
         &self,
         user_id: &UserId,
-        item_uri: &SpotifyUri,
+        item_uri: &ItemUri,
     ) -> ApiResult<Vec<Tag>> {
         self.find_tags_helper(Self::filter_by_item(user_id, item_uri))
             .await
     }
 
-    fn filter_by_user(user_id: &UserId) -> Document {
-        doc! {"user_id": user_id}
-    }
+    /// Find tags that start with a prefix (case-insensitive), for
+    /// autocompletion. Tags are ordered by how many items they're applied to,
+    /// then by how recently they were last applied to an item. `limit` must be
+    /// positive.
+    pub async fn find_tag_suggestions(
+        &self,
+        user_id: &UserId,
+        prefix: &str,
+        limit: i64,
+    ) -> ApiResult<Vec<TagUsageDocument>> {
+        let tag_filter = doc! {
+            "$regex": format!("^{}", escape_regex(prefix)),
+            "$options": "i",
+        };
+        let mut cursor = self
+            .collection
+            .aggregate(
+                vec![
+                    doc! {"$match": {"user_id": user_id, "tags": &tag_filter}},
+                    // Each application records when the tag was applied, which
+                    // is what we want for recency
+                    doc! {"$unwind": "$tag_applications"},
+                    // Items can have other tags that don't match, drop those
+                    doc! {"$match": {"tag_applications.tag": tag_filter}},
+                    doc! {"$group": {
+                        "_id": "$tag_applications.tag",
+                        "count": {"$sum": 1},
+                        "last_used": {"$max": "$tag_applications.tagged_at"},
+                    }},
+                    // Sort by tag as a tiebreaker, to keep output stable
+                    doc! {"$sort": {"count": -1, "last_used": -1, "_id": 1}},
+                    doc! {"$limit": limit},
+                    doc! {"$project": {"tag": "$_id", "count": 1, "_id": 0}},
+                ],
+                None,
+            )
+            .await?;
 
-    fn filter_by_tag(user_id: &UserId, tag: &Tag) -> Document {
-        doc! {"user_id": user_id, "tags":tag}
-    }
+        let mut suggestions = Vec::new();
+        while let Some(doc) = cursor.next().await {
+            suggestions.push(bson::from_document(doc?)?);
+        }
 
-    fn filter_by_item(user_id: &UserId, item_uri: &SpotifyUri) -> Document {
-        doc! {"user_id": user_id, "uri": item_uri}
+        Ok(suggestions)
     }
 
-    /// Internal helper function to count the number of unique tags in the DB
-    /// that match a given filter
-    async fn count_tags_helper(
+    /// Count the items that have each of the given tags, grouped by tag and
+    /// item type. This only counts exact tag matches, not descendants. Tags
+    /// that aren't applied to any items won't appear in the output.
+    pub async fn count_items_by_tags(
         &self,
-        match_filter: Document,
-    ) -> ApiResult<u64> {
+        user_id: &UserId,
+        tags: &[Tag],
+    ) -> ApiResult<Vec<TagItemCountDocument>> {
+        let tags: Vec<Bson> = tags.iter().map(Bson::from).collect();
         let mut cursor = self
             .collection
             .aggregate(
                 vec![
-                    doc! {"$match": match_filter},
+                    doc! {"$match": {"user_id": user_id, "tags": {"$in": &tags}}},
                     doc! {"$unwind": "$tags"},
-                    doc! {"$count": "count"},
+                    // Items can have other tags that we don't care about
+                    doc! {"$match": {"tags": {"$in": tags}}},
+                    // The item type is the middle segment of the URI, e.g.
+                    // spotify:track:<id>
+                    doc! {"$group": {
+                        "_id": {
+                            "tag": "$tags",
+                            "item_type": {
+                                "$arrayElemAt": [{"$split": ["$uri", ":"]}, 1],
+                            },
+                        },
+                        "count": {"$sum": 1},
+                    }},
+                    doc! {"$project": {
+                        "tag": "$_id.tag",
+                        "item_type": "$_id.item_type",
+                        "count": 1,
+                        "_id": 0,
+                    }},
                 ],
                 None,
             )
             .await?;
 
-        // This should return either 0 docs (no tags for this user)
-        // or 1 doc (all other cases). If the doc is present, parse it
-        // to get the tag count
-        let count = match cursor.next().await {
-            Some(doc) => {
-                let count_doc: CountDocument =
-                    mongodb::bson::from_document(doc?)?;
-                count_doc.count
-            }
-            None => 0,
-        };
+        let mut counts = Vec::new();
+        while let Some(doc) = cursor.next().await {
+            counts.push(bson::from_document(doc?)?);
+        }
 
-        Ok(count)
+        Ok(counts)
     }
 
-    /// Internal helper function to fetch and return a list of the unique tags
-    /// in the DB that match a given filter
-    async fn find_tags_helper(
+    /// Find every pair of tags that are applied to at least `min_count` of the
+    /// same items, along with the Jaccard similarity of each pair (shared
+    /// items divided by items that have either tag). Each pair only appears
+    /// once, with the tags in sorted order. The most common pairs come first.
+    ///
+    /// Per-tag counts and pair counts are separate aggregations, so that
+    /// neither one has to fit into a single 16MB document.
+    pub async fn find_tag_cooccurrences(
         &self,
-        match_filter: Document,
-    ) -> ApiResult<Vec<Tag>> {
+        user_id: &UserId,
+        min_count: i64,
+    ) -> ApiResult<Vec<TagCooccurrenceDocument>> {
+        /// Number of items that have both tags, from the pairs aggregation
+        #[derive(Deserialize)]
+        struct TagPair {
+            tag_a: Tag,
+            tag_b: Tag,
+            count: u64,
+        }
+
+        // Number of items for each individual tag
         let mut cursor = self
             .collection
             .aggregate(
                 vec![
-                    doc! {"$match": match_filter},
+                    doc! {"$match": {"user_id": user_id}},
                     doc! {"$unwind": "$tags"},
-                    doc! {"$group": {"_id": "$tags"}},
-                    doc! {"$project": {"tag": "$_id", "_id": 0}},
-                    // Sort tags alphabetically
-                    doc! {"$sort": {"tag": 1}},
+                    doc! {"$group": {"_id": "$tags", "count": {"$sum": 1}}},
+                    doc! {"$project": {"tag": "$_id", "count": 1, "_id": 0}},
                 ],
                 None,
             )
             .await?;
+        let mut tag_counts: HashMap<Tag, u64> = HashMap::new();
+        while let Some(doc) = cursor.next().await {
+            let usage: TagUsageDocument = bson::from_document(doc?)?;
+            tag_counts.insert(usage.tag, usage.count);
+        }
 
-        // Load and deserialize each doc
-        let mut tags = Vec::new();
+        // Number of items for each pair of tags. Unwinding the tags twice
+        // gives every combination of two tags on each item, then we keep just
+        // one ordering of each. The intermediate results can get big, so let
+        // the grouping spill to disk if it needs to.
+        let mut cursor = self
+            .collection
+            .aggregate(
+                vec![
+                    doc! {"$match": {"user_id": user_id}},
+                    doc! {"$project": {"tag_a": "$tags", "tag_b": "$tags"}},
+                    doc! {"$unwind": "$tag_a"},
+                    doc! {"$unwind": "$tag_b"},
+                    doc! {"$match": {"$expr": {"$lt": ["$tag_a", "$tag_b"]}}},
+                    doc! {"$group": {
+                        "_id": {"tag_a": "$tag_a", "tag_b": "$tag_b"},
+                        "count": {"$sum": 1},
+                    }},
+                    doc! {"$match": {"count": {"$gte": min_count}}},
+                    doc! {"$project": {
+                        "_id": 0,
+                        "tag_a": "$_id.tag_a",
+                        "tag_b": "$_id.tag_b",
+                        "count": 1,
+                    }},
+                ],
+                Some(AggregateOptions::builder().allow_disk_use(true).build()),
+            )
+            .await?;
+        let mut cooccurrences = Vec::new();
         while let Some(doc) = cursor.next().await {
-            let doc: TagSummaryDocument = bson::from_document(doc?)?;
-            tags.push(doc.tag)
+            let pair: TagPair = bson::from_document(doc?)?;
+            let count_a = tag_counts.get(&pair.tag_a).copied().unwrap_or(0);
+            let count_b = tag_counts.get(&pair.tag_b).copied().unwrap_or(0);
+            // |A ∩ B| / (|A| + |B| - |A ∩ B|). The two aggregations aren't a
+            // consistent snapshot, so make sure a concurrent write can't
+            // push this out of [0, 1].
+            let union = (count_a + count_b)
+                .saturating_sub(pair.count)
+                .max(pair.count);
+            let jaccard = pair.count as f64 / union as f64;
+            cooccurrences.push(TagCooccurrenceDocument {
+                tag_a: pair.tag_a,
+                tag_b: pair.tag_b,
+                count: pair.count,
+                jaccard,
+            });
         }
 
-        Ok(tags)
+        // Sort by tags as a tiebreaker, to keep output stable
+        cooccurrences.sort_by(|a, b| {
+            b.count
+                .cmp(&a.count)
+                .then(b.jaccard.total_cmp(&a.jaccard))
+                .then_with(|| a.tag_a.cmp(&b.tag_a))
+                .then_with(|| a.tag_b.cmp(&b.tag_b))
+        });
+        Ok(cooccurrences)
     }
-}
 
-// ===== DB Schema =====
-// Below is the schema for each collection in the DB
+    /// Rename a tag on every item that this user has applied it to. If an item
+    /// already has the new tag, the two are merged so the item just ends up
+    /// with a single copy of it. Returns the number of items that had the old
+    /// tag.
+    pub async fn rename_tag(
+        &self,
+        user_id: &UserId,
+        from: &Tag,
+        to: &Tag,
+    ) -> ApiResult<u64> {
+        self.merge_tags(user_id, std::slice::from_ref(from), to)
+            .await
+    }
 
-/// A document in [CollectionName::TaggedItems]. Any item type can be tagged.
-/// The item type can be grabbed via `uri.uri_type`. We avoid storing any data
-/// beyond the URI because it can change, and there's probably legal shit around
-/// it too. So we just fetch it from Spotify on-demand whenever it's needed.
-#[derive(Clone, Debug, Serialize, Deserialize)]
-pub struct TaggedItemDocument {
-    pub user_id: UserId,
-    pub tags: Vec<Tag>,
-    pub uri: SpotifyUri,
-}
+    /// Apply a tag to a single item, creating the item's document if needed.
+    /// Returns the tags that the item had _before_ the update.
+    pub async fn add_tag(
+        &self,
+        user_id: &UserId,
+        item_uri: &ItemUri,
+        tag: &Tag,
+    ) -> ApiResult<Vec<Tag>> {
+        let old_doc = self
+            .collection
+            .find_one_and_update(
+                Self::filter_by_item(user_id, item_uri),
+                Self::update_tags_pipeline(doc! {
+                    "$setUnion": [
+                        {"$ifNull": ["$tags", []]},
+                        {"$literal": [tag]},
+                    ],
+                }),
+                Some(
+                    FindOneAndUpdateOptions::builder()
+                        .upsert(true)
+                        .return_document(ReturnDocument::Before)
+                        .build(),
+                ),
+            )
+            .await?;
+        // No doc means this is the first time the item has been tagged
+        Ok(old_doc.map(|doc| doc.tags).unwrap_or_default())
+    }
 
-/// A Mongo document that counts a single `count` field. Useful when
-/// deserializing the results of an aggregation that ends in a
-/// `{$count:"count"}` step.
-#[derive(Copy, Clone, Debug, Deserialize)]
-struct CountDocument {
-    pub count: u64,
-}
+    /// Remove a tag from a single item. Returns the document as it was
+    /// _before_ the update, or `None` if the user has never tagged the item.
+    pub async fn remove_tag(
+        &self,
+        user_id: &UserId,
+        item_uri: &ItemUri,
+        tag: &Tag,
+    ) -> ApiResult<Option<TaggedItemDocument>> {
+        Ok(self
+            .collection
+            .find_one_and_update(
+                Self::filter_by_item(user_id, item_uri),
+                Self::update_tags_pipeline(doc! {
+                    "$setDifference": [
+                        {"$ifNull": ["$tags", []]},
+                        {"$literal": [tag]},
+                    ],
+                }),
+                Some(
+                    FindOneAndUpdateOptions::builder()
+                        .return_document(ReturnDocument::Before)
+                        .build(),
+                ),
+            )
+            .await?)
+    }
 
-/// A summary of tag information, generated by an unwind query.
-#[derive(Clone, Debug, Deserialize)]
-struct TagSummaryDocument {
-    tag: Tag,
+    /// Fold a group of tags into a single target tag, on every item that this
+    /// user has applied any of the source tags to. The source tags are removed
+    /// and the target is added, without duplicating it on items that already
+    /// have it. Returns the number of items that had at least one of the
+    /// source tags.
+    pub async fn merge_tags(
+        &self,
+        user_id: &UserId,
+        sources: &[Tag],
+        target: &Tag,
+    ) -> ApiResult<u64> {
+        // If the target is also a source, we don't want to remove it below
+        let sources: Vec<Bson> = sources
+            .iter()
+            .filter(|tag| *tag != target)
+            .map(Bson::from)
+            .collect();
+        if sources.is_empty() {
+            return Ok(0);
+        }
+        let result = self
+            .collection
+            .update_many(
+                doc! {"user_id": user_id, "tags": {"$in": &sources}},
+                Self::update_tags_pipeline(doc! {
+                    "$setUnion": [
+                        {"$setDifference": ["$tags", {"$literal": sources}]},
+                        {"$literal": [target]},
+                    ],
+                }),
+                None,
+            )
+            .await?;
+
+        Ok(result.matched_count)
+    }
+
+    /// Replace the full set of tags on a single item, in one atomic update.
+    /// Returns the tags that the item had _before_ the update.
+    pub async fn set_tags(
+        &self,
+        user_id: &UserId,
+        item_uri: &ItemUri,
+        tags: &[Tag],
+    ) -> ApiResult<Vec<Tag>> {
+        let new_tags: Vec<Bson> = tags.iter().map(Bson::from).collect();
+        let old_doc = self
+            .collection
+            .find_one_and_update(
+                Self::filter_by_item(user_id, item_uri),
+                Self::update_tags_pipeline(doc! {"$literal": new_tags}),
+                Some(
+                    FindOneAndUpdateOptions::builder()
+                        // No point in creating a doc with no tags
+                        .upsert(!tags.is_empty())
+                        .return_document(ReturnDocument::Before)
+                        .build(),
+                ),
+            )
+            .await?;
+        Ok(old_doc.map(|doc| doc.tags).unwrap_or_default())
+    }
+
+    /// Add and remove tags on a group of items, with a single bulk write. If
+    /// a tag is in both lists, the removal wins. Items that don't have a
+    /// document yet will get one, as long as there is at least one tag to
+    /// add.
+    pub async fn update_tags_bulk(
+        &self,
+        user_id: &UserId,
+        item_uris: &[ItemUri],
+        add: &[Tag],
+        remove: &[Tag],
+    ) -> ApiResult<()> {
+        if item_uris.is_empty() {
+            return Ok(());
+        }
+
+        let add: Vec<Bson> = add.iter().map(Bson::from).collect();
+        let remove: Vec<Bson> = remove.iter().map(Bson::from).collect();
+        let update_pipeline = Self::update_tags_pipeline(doc! {
+            "$setDifference": [
+                {"$setUnion": [
+                    {"$ifNull": ["$tags", []]},
+                    {"$literal": &add},
+                ]},
+                {"$literal": remove},
+            ],
+        });
+        let updates: Vec<Document> = item_uris
+            .iter()
+            .map(|item_uri| {
+                doc! {
+                    "q": Self::filter_by_item(user_id, item_uri),
+                    "u": update_pipeline.clone(),
+                    "upsert": !add.is_empty(),
+                }
+            })
+            .collect();
+        self.run_bulk_update(updates).await
+    }
+
+    /// Apply a group of changes, each to a different item, with a single bulk
+    /// write. Each change adds and removes tags on its item. This is the
+    /// per-item version of [Self::update_tags_bulk].
+    pub async fn apply_changes(
+        &self,
+        user_id: &UserId,
+        changes: &[ItemTagChange],
+    ) -> ApiResult<()> {
+        if changes.is_empty() {
+            return Ok(());
+        }
+
+        let updates: Vec<Document> = changes
+            .iter()
+            .map(|change| {
+                let added: Vec<Bson> =
+                    change.added.iter().map(Bson::from).collect();
+                let removed: Vec<Bson> =
+                    change.removed.iter().map(Bson::from).collect();
+                doc! {
+                    "q": Self::filter_by_item(user_id, &change.item_uri),
+                    "u": Self::update_tags_pipeline(doc! {
+                        "$setDifference": [
+                            {"$setUnion": [
+                                {"$ifNull": ["$tags", []]},
+                                {"$literal": &added},
+                            ]},
+                            {"$literal": removed},
+                        ],
+                    }),
+                    "upsert": !added.is_empty(),
+                }
+            })
+            .collect();
+        self.run_bulk_update(updates).await
+    }
+
+    /// Run a list of update statements as a single `update` command. Each
+    /// statement should be a document with `q`, `u` and (optionally) `upsert`
+    /// fields.
+    async fn run_bulk_update(&self, updates: Vec<Document>) -> ApiResult<()> {
+        // The driver doesn't have a bulk write API, so run the command by hand
+        let response = self
+            .database
+            .run_command(
+                doc! {
+                    "update": Self::name(),
+                    "updates": updates,
+                    "ordered": false,
+                },
+                None,
+            )
+            .await?;
+
+        // Write errors are reported in the response body, rather than failing
+        // the whole command
+        match response.get_array("writeErrors") {
+            Ok(write_errors) if !write_errors.is_empty() => {
+                Err(ApiError::Unknown {
+                    message: format!(
+                        "Errors in bulk tag update: {:?}",
+                        write_errors
+                    ),
+                    backtrace: Backtrace::capture(),
+                })
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Remove a tag from every item that this user has applied it to. Any item
+    /// that's left without tags afterwards is deleted entirely. Returns the
+    /// number of items that had the tag.
+    pub async fn delete_tag(
+        &self,
+        user_id: &UserId,
+        tag: &Tag,
+    ) -> ApiResult<u64> {
+        // Grab the affected items up front, so we know which ones to clean up
+        // afterwards. Once the tag is gone, there's no way to tell.
+        let item_uris = self
+            .collection
+            .distinct("uri", Self::filter_by_tag(user_id, tag), None)
+            .await?;
+        let result = self
+            .collection
+            .update_many(
+                Self::filter_by_tag(user_id, tag),
+                Self::update_tags_pipeline(doc! {
+                    "$setDifference": ["$tags", {"$literal": [tag]}],
+                }),
+                None,
+            )
+            .await?;
+        self.delete_empty(user_id, item_uris).await?;
+
+        Ok(result.matched_count)
+    }
+
+    /// Set or clear the note on a single item. See [Self::set_field].
+    pub async fn set_note(
+        &self,
+        user_id: &UserId,
+        item_uri: &ItemUri,
+        note: Option<&str>,
+    ) -> ApiResult<()> {
+        self.set_field(user_id, item_uri, "note", note.map(Bson::from))
+            .await
+    }
+
+    /// Set or clear the rating on a single item. See [Self::set_field].
+    pub async fn set_rating(
+        &self,
+        user_id: &UserId,
+        item_uri: &ItemUri,
+        rating: Option<u8>,
+    ) -> ApiResult<()> {
+        let rating = rating.map(|rating| Bson::from(i32::from(rating)));
+        self.set_field(user_id, item_uri, "rating", rating).await
+    }
+
+    /// Set or clear a field on a single item that's independent of its tags,
+    /// such as the note. Setting a field on an item that has never been
+    /// tagged creates a document for it, so the value is kept even if the
+    /// item never gets any tags.
+    async fn set_field(
+        &self,
+        user_id: &UserId,
+        item_uri: &ItemUri,
+        field: &str,
+        value: Option<Bson>,
+    ) -> ApiResult<()> {
+        let filter = Self::filter_by_item(user_id, item_uri);
+        match value {
+            Some(value) => {
+                let now = bson::DateTime::now();
+                let mut set = Document::new();
+                set.insert(field, value);
+                self.collection
+                    .update_one(
+                        filter,
+                        doc! {
+                            "$set": set,
+                            "$setOnInsert": {
+                                "tags": [],
+                                "tag_applications": [],
+                                "created_at": now,
+                                "updated_at": now,
+                            },
+                        },
+                        Some(UpdateOptions::builder().upsert(true).build()),
+                    )
+                    .await?;
+            }
+            None => {
+                let mut unset = Document::new();
+                unset.insert(field, "");
+                self.collection
+                    .update_one(filter, doc! {"$unset": unset}, None)
+                    .await?;
+                self.delete_empty(user_id, vec![item_uri.into()]).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Delete any of the given items that have no tags, note or rating left,
+    /// since there's no point in keeping them around. Only the given items are
+    /// touched, so other empty documents are left alone.
+    async fn delete_empty(
+        &self,
+        user_id: &UserId,
+        item_uris: Vec<Bson>,
+    ) -> ApiResult<()> {
+        if item_uris.is_empty() {
+            return Ok(());
+        }
+        self.collection
+            .delete_many(
+                doc! {
+                    "user_id": user_id,
+                    "uri": {"$in": item_uris},
+                    "tags": {"$size": 0},
+                    "note": null,
+                    "rating": null,
+                },
+                None,
+            )
+            .await?;
+        Ok(())
+    }
+
+    /// Fill in timestamps for items that were tagged before we started
+    /// tracking them. The best guess we have for when these were tagged is
+    /// when the document was created, which is embedded in its object ID.
+    pub async fn backfill_timestamps(&self) -> ApiResult<u64> {
+        let result = self
+            .collection
+            .update_many(
+                doc! {"created_at": {"$exists": false}},
+                vec![doc! {
+                    "$set": {
+                        "created_at": {"$toDate": "$_id"},
+                        "updated_at": {"$toDate": "$_id"},
+                        "tag_applications": {"$map": {
+                            "input": "$tags",
+                            "as": "tag",
+                            "in": {
+                                "tag": "$$tag",
+                                "tagged_at": {"$toDate": "$_id"},
+                            },
+                        }},
+                    },
+                }],
+                None,
+            )
+            .await?;
+        Ok(result.modified_count)
+    }
+
+    /// Bring every stored tag in line with the current [TagRules]. If multiple
+    /// tags on an item normalize to the same tag, they're merged, keeping the
+    /// earliest application time. Items that end up with no tags, note or
+    /// rating are deleted. Only items with an out-of-date tag are touched.
+    pub async fn normalize_tags(&self) -> ApiResult<u64> {
+        let outdated = find_unnormalized_tags(&self.collection, "tags").await?;
+        if outdated.is_empty() {
+            return Ok(0);
+        }
+
+        let rules = TagRules::get();
+        let collection =
+            self.collection.clone_with_type::<RawTaggedItemDocument>();
+        let outdated: Vec<&str> =
+            outdated.iter().map(|(raw, _)| raw.as_str()).collect();
+        let mut cursor = collection
+            .find(doc! {"tags": {"$in": outdated}}, None)
+            .await?;
+
+        let mut modified = 0;
+        while let Some(item) = cursor.try_next().await? {
+            let filter = doc! {"_id": item.id};
+            let tags: Vec<Tag> = item
+                .tags
+                .iter()
+                .filter_map(|tag| rules.sanitize(tag))
+                .unique()
+                .collect();
+            if tags.is_empty() && item.note.is_none() && item.rating.is_none() {
+                collection.delete_one(filter, None).await?;
+                modified += 1;
+                continue;
+            }
+
+            let mut tagged_at: HashMap<Tag, bson::DateTime> = HashMap::new();
+            for application in &item.tag_applications {
+                if let Some(tag) = rules.sanitize(&application.tag) {
+                    tagged_at
+                        .entry(tag)
+                        .and_modify(|time| {
+                            *time = (*time).min(application.tagged_at)
+                        })
+                        .or_insert(application.tagged_at);
+                }
+            }
+            let tag_applications: Vec<Document> = tags
+                .iter()
+                .map(|tag| {
+                    let tagged_at = tagged_at
+                        .get(tag)
+                        .copied()
+                        .unwrap_or_else(bson::DateTime::now);
+                    doc! {"tag": tag, "tagged_at": tagged_at}
+                })
+                .collect();
+            let tags: Vec<Bson> = tags.iter().map(Bson::from).collect();
+
+            collection
+                .update_one(
+                    filter,
+                    doc! {
+                        "$set": {
+                            "tags": tags,
+                            "tag_applications": tag_applications,
+                        },
+                    },
+                    None,
+                )
+                .await?;
+            modified += 1;
+        }
+        Ok(modified)
+    }
+
+    /// Build an update pipeline that replaces an item's tags with the result
+    /// of a Mongo expression, and keeps all the item's timestamps up to date.
+    /// Tags that the item already had keep their original timestamp, and new
+    /// ones get the current time. Any tags in the expression should be wrapped
+    /// in `$literal`, so they don't get parsed as field paths.
+    ///
+    /// We need a pipeline rather than update operators because we're reading
+    /// and writing the same fields in a single update, which Mongo won't allow
+    /// with operators.
+    fn update_tags_pipeline(tags: Document) -> Vec<Document> {
+        vec![
+            // Within a single stage, field references see the old values
+            doc! {
+                "$set": {
+                    "tags": &tags,
+                    "created_at": {"$ifNull": ["$created_at", "$$NOW"]},
+                    // Only bump the update time if the tags actually changed
+                    "updated_at": {
+                        "$cond": [
+                            {"$setEquals": [&tags, {"$ifNull": ["$tags", []]}]},
+                            {"$ifNull": ["$updated_at", "$$NOW"]},
+                            "$$NOW",
+                        ],
+                    },
+                },
+            },
+            // Rebuild the timestamp for each tag from the new tag list
+            doc! {
+                "$set": {
+                    "tag_applications": {"$map": {
+                        "input": "$tags",
+                        "as": "tag",
+                        "in": {
+                            "tag": "$$tag",
+                            "tagged_at": {"$ifNull": [
+                                // Grab the existing timestamp, if any
+                                {"$arrayElemAt": [
+                                    {"$map": {
+                                        "input": {"$filter": {
+                                            "input": {"$ifNull": [
+                                                "$tag_applications",
+                                                [],
+                                            ]},
+                                            "cond": {
+                                                "$eq": ["$$this.tag", "$$tag"],
+                                            },
+                                        }},
+                                        "in": "$$this.tagged_at",
+                                    }},
+                                    0,
+                                ]},
+                                "$$NOW",
+                            ]},
+                        },
+                    }},
+                },
+            },
+        ]
+    }
+
+    fn filter_by_user(user_id: &UserId) -> Document {
+        doc! {"user_id": user_id}
+    }
+
+    fn filter_by_tag(user_id: &UserId, tag: &Tag) -> Document {
+        doc! {"user_id": user_id, "tags":tag}
+    }
+
+    /// Like [Self::filter_by_tag], but optionally also matches any tag
+    /// nested under the given one in the tag hierarchy, and optionally only
+    /// matches items rated within a range. Items in `smart_uris` match
+    /// the tag even if it isn't stored on them.
+    fn filter_by_tag_tree(
+        user_id: &UserId,
+        tag: &Tag,
+        include_descendants: bool,
+        rating: Option<&RatingFilter>,
+        smart_uris: &[ItemUri],
+    ) -> Document {
+        let tag_filter = if include_descendants {
+            // Match the tag itself, or anything that starts with the tag
+            // followed by a separator. Anchored regexes can still use indexes.
+            let pattern =
+                format!("^{}($|{})", escape_regex(tag.tag()), TAG_SEPARATOR);
+            doc! {"tags": {"$regex": pattern}}
+        } else {
+            doc! {"tags": tag}
+        };
+        let mut filter = Self::filter_by_user(user_id);
+        if smart_uris.is_empty() {
+            filter.extend(tag_filter);
+        } else {
+            let smart_uris: Vec<Bson> =
+                smart_uris.iter().map(Bson::from).collect();
+            filter.insert(
+                "$or",
+                vec![tag_filter, doc! {"uri": {"$in": smart_uris}}],
+            );
+        }
+        if let Some(rating) = rating {
+            filter.extend(Self::filter_by_rating(rating));
+        }
+        filter
+    }
+
+    /// Match items rated within a range. Unrated items never match.
+    fn filter_by_rating(rating: &RatingFilter) -> Document {
+        // This also makes sure comparisons can't match a missing rating
+        let mut range = doc! {"$type": "number"};
+        if let Some(min) = rating.min {
+            range.insert("$gte", i32::from(min));
+        }
+        if let Some(max) = rating.max {
+            range.insert("$lte", i32::from(max));
+        }
+        doc! {"rating": range}
+    }
+
+    fn filter_by_expression(user_id: &UserId, filter: &TagFilter) -> Document {
+        doc! {"user_id": user_id, "$and": [Self::compile_filter(filter)]}
+    }
+
+    /// Recursively convert a tag filter expression into a Mongo query. The
+    /// output doesn't filter by user, so it should be wrapped by
+    /// [Self::filter_by_expression]. Filters are capped at
+    /// [crate::graphql::MAX_FILTER_DEPTH] when they're built, which keeps the
+    /// recursion here bounded too.
+    fn compile_filter(filter: &TagFilter) -> Document {
+        match filter {
+            TagFilter::Tag(tag) => doc! {"tags": tag},
+            // The type is encoded in the URI, e.g. spotify:track:<id>, and
+            // it's the same for every provider
+            TagFilter::ItemType(item_type) => {
+                doc! {"uri": {"$regex": format!("^[^:]+:{}:", item_type)}}
+            }
+            TagFilter::NoteContains(text) => {
+                doc! {"note": {"$regex": escape_regex(text), "$options": "i"}}
+            }
+            TagFilter::Rating(rating) => Self::filter_by_rating(rating),
+            TagFilter::Items(uris) => {
+                let uris: Vec<Bson> = uris.iter().map(Bson::from).collect();
+                doc! {"uri": {"$in": uris}}
+            }
+            // Mongo rejects empty $and/$or arrays, so handle those manually
+            TagFilter::And(filters) if filters.is_empty() => doc! {},
+            TagFilter::Or(filters) if filters.is_empty() => {
+                doc! {"$expr": false}
+            }
+            TagFilter::And(filters) => {
+                let filters: Vec<Document> =
+                    filters.iter().map(Self::compile_filter).collect();
+                doc! {"$and": filters}
+            }
+            TagFilter::Or(filters) => {
+                let filters: Vec<Document> =
+                    filters.iter().map(Self::compile_filter).collect();
+                doc! {"$or": filters}
+            }
+            // $not only works on a single field, so use $nor instead
+            TagFilter::Not(filter) => {
+                doc! {"$nor": [Self::compile_filter(filter)]}
+            }
+        }
+    }
+
+    fn filter_by_item(user_id: &UserId, item_uri: &ItemUri) -> Document {
+        doc! {"user_id": user_id, "uri": item_uri}
+    }
+
+    /// Internal helper function to count the number of unique tags in the DB
+    /// that match a given filter
+    async fn count_tags_helper(
+        &self,
+        match_filter: Document,
+    ) -> ApiResult<u64> {
+        let mut cursor = self
+            .collection
+            .aggregate(
+                vec![
+                    doc! {"$match": match_filter},
+                    doc! {"$unwind": "$tags"},
+                    doc! {"$count": "count"},
+                ],
+                None,
+            )
+            .await?;
+
+        // This should return either 0 docs (no tags for this user)
+        // or 1 doc (all other cases). If the doc is present, parse it
+        // to get the tag count
+        let count = match cursor.next().await {
+            Some(doc) => {
+                let count_doc: CountDocument =
+                    mongodb::bson::from_document(doc?)?;
+                count_doc.count
+            }
+            None => 0,
+        };
+
+        Ok(count)
+    }
+
+    /// Internal helper function to fetch and return a list of the unique tags
+    /// in the DB that match a given filter
+    async fn find_tags_helper(
+        &self,
+        match_filter: Document,
+    ) -> ApiResult<Vec<Tag>> {
+        let mut cursor = self
+            .collection
+            .aggregate(
+                vec![
+                    doc! {"$match": match_filter},
+                    doc! {"$unwind": "$tags"},
+                    doc! {"$group": {"_id": "$tags"}},
+                    doc! {"$project": {"tag": "$_id", "_id": 0}},
+                    // Sort tags alphabetically
+                    doc! {"$sort": {"tag": 1}},
+                ],
+                None,
+            )
+            .await?;
+
+        // Load and deserialize each doc
+        let mut tags = Vec::new();
+        while let Some(doc) = cursor.next().await {
+            let doc: TagSummaryDocument = bson::from_document(doc?)?;
+            tags.push(doc.tag)
+        }
+
+        Ok(tags)
+    }
+}
+
+/// A wrapper around the `tags` collection, which holds metadata for tags. Tags
+/// themselves live in [TaggedItemDocument], so a tag may not have a document
+/// here (e.g. if it was created before this collection existed). Callers
+/// should treat a missing document the same as one with no metadata set.
+///
+/// Like with [TaggedItemsCollection], every method here filters by user ID.
+#[derive(Debug, Deref, From)]
+pub struct TagsCollection {
+    collection: Collection<TagDocument>,
+}
+
+impl TagsCollection {
+    // Get the name of this collection, as defined in the DB
+    pub fn name() -> &'static str {
+        "tags"
+    }
+
+    /// Get the metadata for a single tag. Returns `None` if the tag doesn't
+    /// have a metadata document.
+    pub async fn find_by_tag(
+        &self,
+        user_id: &UserId,
+        tag: &Tag,
+    ) -> ApiResult<Option<TagDocument>> {
+        Ok(self
+            .collection
+            .find_one(Self::filter_by_tag(user_id, tag), None)
+            .await?)
+    }
+
+    /// Get the metadata for a group of tags. Tags that don't have a metadata
+    /// document are skipped.
+    pub async fn find_by_tags(
+        &self,
+        user_id: &UserId,
+        tags: &[Tag],
+    ) -> ApiResult<Vec<TagDocument>> {
+        if tags.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let tags: Vec<Bson> = tags.iter().map(Bson::from).collect();
+        Ok(self
+            .collection
+            .find(doc! {"user_id": user_id, "tag": {"$in": tags}}, None)
+            .await?
+            .try_collect()
+            .await?)
+    }
+
+    /// Write back a group of previously loaded metadata documents, replacing
+    /// whatever is currently stored for each tag
+    pub async fn restore(&self, docs: &[TagDocument]) -> ApiResult<()> {
+        for doc in docs {
+            self.collection
+                .replace_one(
+                    Self::filter_by_tag(&doc.user_id, &doc.tag),
+                    doc,
+                    Some(ReplaceOptions::builder().upsert(true).build()),
+                )
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Make sure a metadata document exists for a tag. If it doesn't, an empty
+    /// one will be created, with the current time as the creation date. This
+    /// should be called whenever a tag is applied to an item.
+    pub async fn ensure_exists(
+        &self,
+        user_id: &UserId,
+        tag: &Tag,
+    ) -> ApiResult<()> {
+        self.collection
+            .update_one(
+                Self::filter_by_tag(user_id, tag),
+                doc! {"$setOnInsert": {"created_at": bson::DateTime::now()}},
+                Some(UpdateOptions::builder().upsert(true).build()),
+            )
+            .await?;
+        Ok(())
+    }
+
+    /// Replace all the user-editable metadata fields on a tag, creating its
+    /// document if necessary. Returns the updated document.
+    pub async fn update_metadata(
+        &self,
+        user_id: &UserId,
+        tag: &Tag,
+        description: Option<&str>,
+        color: Option<&str>,
+        icon: Option<&str>,
+    ) -> ApiResult<TagDocument> {
+        self.collection
+            .find_one_and_update(
+                Self::filter_by_tag(user_id, tag),
+                doc! {
+                    "$set": {
+                        "description": description,
+                        "color": color,
+                        "icon": icon,
+                    },
+                    "$setOnInsert": {"created_at": bson::DateTime::now()},
+                },
+                Some(
+                    FindOneAndUpdateOptions::builder()
+                        .upsert(true)
+                        .return_document(ReturnDocument::After)
+                        .build(),
+                ),
+            )
+            .await?
+            // This shouldn't be possible because we have upsert=true, but just
+            // to be safe
+            .ok_or_else(|| ApiError::Unknown {
+                message: "No result from findOneAndUpdate".into(),
+                backtrace: Backtrace::capture(),
+            })
+    }
+
+    /// Move a tag's metadata over to a new name. If the new tag already has
+    /// metadata, that takes precedence and the old tag's metadata is dropped.
+    pub async fn rename_tag(
+        &self,
+        user_id: &UserId,
+        from: &Tag,
+        to: &Tag,
+    ) -> ApiResult<()> {
+        if from == to {
+            return Ok(());
+        }
+
+        if self.find_by_tag(user_id, to).await?.is_some() {
+            self.delete_tags(user_id, std::slice::from_ref(from)).await
+        } else {
+            self.collection
+                .update_one(
+                    Self::filter_by_tag(user_id, from),
+                    doc! {"$set": {"tag": to}},
+                    None,
+                )
+                .await?;
+            Ok(())
+        }
+    }
+
+    /// Delete the metadata for a group of tags. This should be called whenever
+    /// a tag is no longer applied to any items.
+    pub async fn delete_tags(
+        &self,
+        user_id: &UserId,
+        tags: &[Tag],
+    ) -> ApiResult<()> {
+        let tags: Vec<Bson> = tags.iter().map(Bson::from).collect();
+        self.collection
+            .delete_many(doc! {"user_id": user_id, "tag": {"$in": tags}}, None)
+            .await?;
+        Ok(())
+    }
+
+    /// Bring every stored tag in line with the current [TagRules]. When
+    /// multiple documents for a user normalize to the same tag, they're merged
+    /// into one. Metadata from the document that was already normalized takes
+    /// precedence, and any fields it's missing are filled in from the others.
+    /// Documents for tags that can't be salvaged are deleted.
+    pub async fn normalize_tags(&self) -> ApiResult<u64> {
+        let outdated = find_unnormalized_tags(&self.collection, "tag").await?;
+        if outdated.is_empty() {
+            return Ok(0);
+        }
+
+        // Grab the out-of-date docs, plus any docs they could collide with
+        let rules = TagRules::get();
+        let collection = self.collection.clone_with_type::<RawTagDocument>();
+        let tags: Vec<Bson> = outdated
+            .iter()
+            .flat_map(|(raw, tag)| {
+                iter::once(Bson::from(raw.as_str()))
+                    .chain(tag.as_ref().map(Bson::from))
+            })
+            .collect();
+        let docs: Vec<RawTagDocument> = collection
+            .find(doc! {"tag": {"$in": tags}}, None)
+            .await?
+            .try_collect()
+            .await?;
+
+        let mut groups: HashMap<(String, Tag), Vec<RawTagDocument>> =
+            HashMap::new();
+        let mut deleted: Vec<ObjectId> = Vec::new();
+        for doc in docs {
+            match rules.sanitize(&doc.tag) {
+                Some(tag) => groups
+                    .entry((doc.user_id.clone(), tag))
+                    .or_default()
+                    .push(doc),
+                None => deleted.push(doc.id),
+            }
+        }
+
+        let mut modified = 0;
+        for ((_, tag), mut docs) in groups {
+            // Docs that are already normalized go first
+            docs.sort_by_key(|doc| doc.tag != tag.tag());
+            let description =
+                docs.iter().find_map(|doc| doc.description.clone());
+            let color = docs.iter().find_map(|doc| doc.color.clone());
+            let icon = docs.iter().find_map(|doc| doc.icon.clone());
+            let created_at = docs.iter().map(|doc| doc.created_at).min();
+            let (keep, others) = match docs.split_first() {
+                Some(split) => split,
+                None => continue,
+            };
+            if others.is_empty() && keep.tag == tag.tag() {
+                continue;
+            }
+
+            deleted.extend(others.iter().map(|doc| doc.id));
+            collection
+                .update_one(
+                    doc! {"_id": keep.id},
+                    doc! {
+                        "$set": {
+                            "tag": &tag,
+                            "description": description,
+                            "color": color,
+                            "icon": icon,
+                            "created_at": created_at,
+                        },
+                    },
+                    None,
+                )
+                .await?;
+            modified += 1;
+        }
+
+        if !deleted.is_empty() {
+            modified += collection
+                .delete_many(doc! {"_id": {"$in": deleted}}, None)
+                .await?
+                .deleted_count;
+        }
+        Ok(modified)
+    }
+
+    fn filter_by_tag(user_id: &UserId, tag: &Tag) -> Document {
+        doc! {"user_id": user_id, "tag": tag}
+    }
+}
+
+/// A wrapper around the `undoEntries` collection. Each entry holds everything
+/// needed to revert a single tag mutation. Entries expire after a configurable
+/// amount of time, after which the mutation can no longer be undone.
+#[derive(Debug, Deref)]
+pub struct UndoEntriesCollection {
+    #[deref]
+    collection: Collection<UndoEntryDocument>,
+    /// How long each entry is valid for, after it's created
+    expiry: Duration,
+}
+
+impl UndoEntriesCollection {
+    // Get the name of this collection, as defined in the DB
+    pub fn name() -> &'static str {
+        "undoEntries"
+    }
+
+    /// Save a group of changes so they can be undone later. Returns the ID of
+    /// the new entry, which is used as the undo token.
+    pub async fn create(
+        &self,
+        user_id: &UserId,
+        item_changes: Vec<ItemTagChange>,
+        tag_metadata: Vec<TagDocument>,
+    ) -> ApiResult<ObjectId> {
+        let now = SystemTime::now();
+        let entry = UndoEntryDocument {
+            id: ObjectId::new(),
+            user_id: user_id.clone(),
+            expires_at: (now + self.expiry).into(),
+            item_changes,
+            tag_metadata,
+        };
+        self.collection.insert_one(&entry, None).await?;
+        Ok(entry.id)
+    }
+
+    /// Create a TTL index so that Mongo deletes expired entries for us. Mongo
+    /// only sweeps expired documents about once a minute, so reads still have
+    /// to check the expiry themselves.
+    pub async fn create_indexes(&self) -> ApiResult<()> {
+        self.collection
+            .create_index(
+                IndexModel::builder()
+                    .keys(doc! {"expires_at": 1})
+                    .options(
+                        IndexOptions::builder()
+                            .expire_after(Duration::from_secs(0))
+                            .build(),
+                    )
+                    .build(),
+                None,
+            )
+            .await?;
+        Ok(())
+    }
+
+    /// Get an entry by ID and delete it in a single atomic operation, so that
+    /// a token can only ever be used once, even by concurrent requests.
+    /// Returns `None` if the entry doesn't exist, belongs to another user, or
+    /// has expired.
+    pub async fn claim(
+        &self,
+        user_id: &UserId,
+        id: ObjectId,
+    ) -> ApiResult<Option<UndoEntryDocument>> {
+        Ok(self
+            .collection
+            .find_one_and_delete(
+                doc! {
+                    "_id": id,
+                    "user_id": user_id,
+                    "expires_at": {"$gt": bson::DateTime::now()},
+                },
+                None,
+            )
+            .await?)
+    }
+
+    /// Put back an entry that was claimed but couldn't be applied, so that
+    /// the user can try again later
+    pub async fn unclaim(&self, entry: &UndoEntryDocument) -> ApiResult<()> {
+        self.collection.insert_one(entry, None).await?;
+        Ok(())
+    }
+}
+
+/// A wrapper around the `tagEvents` collection. This is an append-only log of
+/// every tag that's been added to or removed from an item.
+#[derive(Debug, Deref, From)]
+pub struct TagEventsCollection {
+    collection: Collection<TagEventDocument>,
+}
+
+impl TagEventsCollection {
+    // Get the name of this collection, as defined in the DB
+    pub fn name() -> &'static str {
+        "tagEvents"
+    }
+
+    /// Record one event for each tag that was added or removed in a group of
+    /// changes. All the events get the current time as their timestamp.
+    pub async fn record(
+        &self,
+        user_id: &UserId,
+        changes: &[ItemTagChange],
+    ) -> ApiResult<()> {
+        let timestamp = bson::DateTime::now();
+        let events: Vec<TagEventDocument> = changes
+            .iter()
+            .flat_map(|change| {
+                let added =
+                    change.added.iter().map(|tag| (tag, TagAction::Add));
+                let removed =
+                    change.removed.iter().map(|tag| (tag, TagAction::Remove));
+                added.chain(removed).map(move |(tag, action)| {
+                    TagEventDocument {
+                        user_id: user_id.clone(),
+                        item_uri: change.item_uri.clone(),
+                        tag: tag.clone(),
+                        action,
+                        timestamp,
+                    }
+                })
+            })
+            .collect();
+
+        // Mongo rejects empty inserts
+        if !events.is_empty() {
+            self.collection.insert_many(events, None).await?;
+        }
+        Ok(())
+    }
+
+    /// Get a page of events, newest first. Events can optionally be filtered
+    /// by item and/or tag. `limit` must be positive, because Mongo treats a
+    /// limit of 0 as no limit at all.
+    pub async fn find_events(
+        &self,
+        user_id: &UserId,
+        item_uri: Option<&ItemUri>,
+        tag: Option<&Tag>,
+        offset: u64,
+        limit: i64,
+    ) -> ApiResult<Cursor<TagEventDocument>> {
+        Ok(self
+            .collection
+            .find(
+                Self::filter_events(user_id, item_uri, tag),
+                Some(
+                    FindOptions::builder()
+                        // IDs break ties between events from the same write
+                        .sort(doc! {"timestamp": -1, "_id": -1})
+                        .skip(offset)
+                        .limit(limit)
+                        .build(),
+                ),
+            )
+            .await?)
+    }
+
+    /// Count the events that match an optional item and/or tag
+    pub async fn count_events(
+        &self,
+        user_id: &UserId,
+        item_uri: Option<&ItemUri>,
+        tag: Option<&Tag>,
+    ) -> ApiResult<u64> {
+        Ok(self
+            .collection
+            .count_documents(Self::filter_events(user_id, item_uri, tag), None)
+            .await?)
+    }
+
+    /// Bring every stored tag in line with the current [TagRules]. Events for
+    /// tags that can't be salvaged are deleted.
+    pub async fn normalize_tags(&self) -> ApiResult<u64> {
+        let mut modified = 0;
+        for (raw, tag) in
+            find_unnormalized_tags(&self.collection, "tag").await?
+        {
+            let filter = doc! {"tag": raw};
+            modified += match tag {
+                Some(tag) => {
+                    self.collection
+                        .update_many(filter, doc! {"$set": {"tag": &tag}}, None)
+                        .await?
+                        .modified_count
+                }
+                None => {
+                    self.collection
+                        .delete_many(filter, None)
+                        .await?
+                        .deleted_count
+                }
+            };
+        }
+        Ok(modified)
+    }
+
+    fn filter_events(
+        user_id: &UserId,
+        item_uri: Option<&ItemUri>,
+        tag: Option<&Tag>,
+    ) -> Document {
+        let mut filter = doc! {"user_id": user_id};
+        if let Some(item_uri) = item_uri {
+            filter.insert("item_uri", item_uri);
+        }
+        if let Some(tag) = tag {
+            filter.insert("tag", tag);
+        }
+        filter
+    }
+}
+
+/// A wrapper around the `smartTags` collection. Each document defines the
+/// rules for a single smart tag. Smart tags are never stored on items, so this
+/// is the only place they exist in the DB.
+///
+/// Like with [TaggedItemsCollection], every method here filters by user ID.
+#[derive(Debug, Deref, From)]
+pub struct SmartTagsCollection {
+    collection: Collection<SmartTagDocument>,
+}
+
+impl SmartTagsCollection {
+    // Get the name of this collection, as defined in the DB
+    pub fn name() -> &'static str {
+        "smartTags"
+    }
+
+    /// Get every smart tag defined by a user, sorted by tag
+    pub async fn find_all(
+        &self,
+        user_id: &UserId,
+    ) -> ApiResult<Vec<SmartTagDocument>> {
+        Ok(self
+            .collection
+            .find(
+                doc! {"user_id": user_id},
+                Some(FindOptions::builder().sort(doc! {"tag": 1}).build()),
+            )
+            .await?
+            .try_collect()
+            .await?)
+    }
+
+    /// Create a smart tag, or replace the conditions on an existing one.
+    /// Returns the saved document.
+    pub async fn save(
+        &self,
+        user_id: &UserId,
+        tag: &Tag,
+        conditions: Vec<SmartTagCondition>,
+    ) -> ApiResult<SmartTagDocument> {
+        let doc = SmartTagDocument {
+            user_id: user_id.clone(),
+            tag: tag.clone(),
+            conditions,
+        };
+        self.collection
+            .replace_one(
+                Self::filter_by_tag(user_id, tag),
+                &doc,
+                Some(ReplaceOptions::builder().upsert(true).build()),
+            )
+            .await?;
+        Ok(doc)
+    }
+
+    /// Delete a smart tag. Returns the deleted document, or `None` if there
+    /// was no smart tag by that name.
+    pub async fn delete(
+        &self,
+        user_id: &UserId,
+        tag: &Tag,
+    ) -> ApiResult<Option<SmartTagDocument>> {
+        Ok(self
+            .collection
+            .find_one_and_delete(Self::filter_by_tag(user_id, tag), None)
+            .await?)
+    }
+
+    /// Bring every smart tag in line with the current [TagRules]. Smart tags
+    /// that can't be salvaged are deleted.
+    pub async fn normalize_tags(&self) -> ApiResult<u64> {
+        let mut modified = 0;
+        for (raw, tag) in
+            find_unnormalized_tags(&self.collection, "tag").await?
+        {
+            let filter = doc! {"tag": raw};
+            modified += match tag {
+                Some(tag) => {
+                    self.collection
+                        .update_many(filter, doc! {"$set": {"tag": &tag}}, None)
+                        .await?
+                        .modified_count
+                }
+                None => {
+                    self.collection
+                        .delete_many(filter, None)
+                        .await?
+                        .deleted_count
+                }
+            };
+        }
+        Ok(modified)
+    }
+
+    fn filter_by_tag(user_id: &UserId, tag: &Tag) -> Document {
+        doc! {"user_id": user_id, "tag": tag}
+    }
+}
+
+/// A wrapper around the `migrations` collection. This holds a single document,
+/// which records how far the DB has been migrated. Unlike the other
+/// collections, this isn't scoped to a user.
+#[derive(Debug, Deref, From)]
+pub struct MigrationsCollection {
+    collection: Collection<MigrationStateDocument>,
+}
+
+impl MigrationsCollection {
+    /// The ID of the one and only document in this collection
+    const STATE_ID: &'static str = "state";
+
+    // Get the name of this collection, as defined in the DB
+    pub fn name() -> &'static str {
+        "migrations"
+    }
+
+    /// Get the current migration state. If migrations have never been run,
+    /// this is the default state, which will run every migration.
+    pub async fn find_state(&self) -> ApiResult<MigrationStateDocument> {
+        Ok(self
+            .collection
+            .find_one(doc! {"_id": Self::STATE_ID}, None)
+            .await?
+            .unwrap_or_default())
+    }
+
+    /// Record the migration state, after migrations have been run
+    pub async fn save_state(
+        &self,
+        state: &MigrationStateDocument,
+    ) -> ApiResult<()> {
+        self.collection
+            .replace_one(
+                doc! {"_id": Self::STATE_ID},
+                state,
+                Some(ReplaceOptions::builder().upsert(true).build()),
+            )
+            .await?;
+        Ok(())
+    }
+}
+
+/// Find all the distinct tags stored in a field that don't follow the current
+/// [TagRules]. Returns each stored tag alongside what it should be replaced
+/// with, which is `None` if the tag can't be salvaged at all.
+async fn find_unnormalized_tags<T>(
+    collection: &Collection<T>,
+    field: &str,
+) -> ApiResult<Vec<(String, Option<Tag>)>> {
+    let rules = TagRules::get();
+    Ok(collection
+        .distinct(field, None, None)
+        .await?
+        .into_iter()
+        .filter_map(|value| match value {
+            Bson::String(raw) => match rules.sanitize(&raw) {
+                Some(tag) if tag.tag() == raw => None,
+                tag => Some((raw, tag)),
+            },
+            _ => None,
+        })
+        .collect())
+}
+
+/// Escape all regex metacharacters in a string, so that it can be embedded in
+/// a Mongo `$regex` and only match itself
+fn escape_regex(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        if "\\.+*?()|[]{}^$".contains(c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+// ===== DB Schema =====
+// Below is the schema for each collection in the DB
+
+/// A document in [CollectionName::TaggedItems]. Any item type can be tagged.
+/// The item type can be grabbed via `uri.uri_type`. We avoid storing any data
+/// beyond the URI because it can change, and there's probably legal shit around
+/// it too. So we just fetch it from Spotify on-demand whenever it's needed.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TaggedItemDocument {
+    pub user_id: UserId,
+    pub tags: Vec<Tag>,
+    pub uri: ItemUri,
+    /// When each tag in `tags` was applied to the item. This is kept in sync
+    /// with `tags`, which remains the source of truth for queries.
+    #[serde(default)]
+    pub tag_applications: Vec<TagApplication>,
+    /// `None` for documents that predate timestamps and haven't been migrated
+    /// yet. See [TaggedItemsCollection::backfill_timestamps].
+    pub created_at: Option<bson::DateTime>,
+    /// The last time the item's tags changed
+    pub updated_at: Option<bson::DateTime>,
+    /// A free-text note from the user about the item. An item can have a
+    /// note without any tags, in which case its document is kept around
+    /// just for the note.
+    pub note: Option<String>,
+    /// The user's personal rating of the item, from 0 to 100. Clients can map
+    /// that onto whatever scale they display, e.g. 5 stars. Like the note,
+    /// this is kept even if the item has no tags.
+    pub rating: Option<u8>,
+}
+
+/// A record of a tag being applied to an item, nested in [TaggedItemDocument]
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TagApplication {
+    pub tag: Tag,
+    pub tagged_at: bson::DateTime,
+}
+
+/// A document in the `tags` collection. This holds metadata for a single tag.
+/// Each document is uniquely identified by the combination of user ID and
+/// tag.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TagDocument {
+    pub user_id: UserId,
+    pub tag: Tag,
+    pub description: Option<String>,
+    /// Any CSS-compatible color
+    pub color: Option<String>,
+    /// Generally an emoji, but can be any short string
+    pub icon: Option<String>,
+    pub created_at: bson::DateTime,
+}
+
+/// A [TaggedItemDocument] with its tags left as plain strings, so that tags
+/// that are invalid under the current [TagRules] can still be loaded and fixed
+#[derive(Clone, Debug, Deserialize)]
+struct RawTaggedItemDocument {
+    #[serde(rename = "_id")]
+    id: ObjectId,
+    tags: Vec<String>,
+    #[serde(default)]
+    tag_applications: Vec<RawTagApplication>,
+    note: Option<String>,
+    rating: Option<i32>,
+}
+
+/// A [TagApplication] with its tag left as a plain string
+#[derive(Clone, Debug, Deserialize)]
+struct RawTagApplication {
+    tag: String,
+    tagged_at: bson::DateTime,
+}
+
+/// A [TagDocument] with its tag left as a plain string
+#[derive(Clone, Debug, Deserialize)]
+struct RawTagDocument {
+    #[serde(rename = "_id")]
+    id: ObjectId,
+    user_id: String,
+    tag: String,
+    description: Option<String>,
+    color: Option<String>,
+    icon: Option<String>,
+    created_at: bson::DateTime,
+}
+
+/// A Mongo document that counts a single `count` field. Useful when
+/// deserializing the results of an aggregation that ends in a
+/// `{$count:"count"}` step.
+#[derive(Copy, Clone, Debug, Deserialize)]
+struct CountDocument {
+    pub count: u64,
+}
+
+/// A change to the tags on a single item, as the result of a mutation
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ItemTagChange {
+    pub item_uri: ItemUri,
+    /// Tags that the item didn't have before the change
+    pub added: Vec<Tag>,
+    /// Tags that the item had before the change, but not after
+    pub removed: Vec<Tag>,
+}
+
+impl ItemTagChange {
+    /// Compute the change between an item's old and new tags
+    pub fn diff(item_uri: ItemUri, before: &[Tag], after: &[Tag]) -> Self {
+        Self {
+            item_uri,
+            added: after
+                .iter()
+                .filter(|tag| !before.contains(tag))
+                .cloned()
+                .collect(),
+            removed: before
+                .iter()
+                .filter(|tag| !after.contains(tag))
+                .cloned()
+                .collect(),
+        }
+    }
+
+    /// Did this change actually do anything?
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty()
+    }
+
+    /// Is this change still in effect on an item with the given tags? That's
+    /// the case if the item has every tag that the change added, and none that
+    /// it removed. If not, the item has been changed since.
+    pub fn is_current(&self, tags: &[Tag]) -> bool {
+        self.added.iter().all(|tag| tags.contains(tag))
+            && !self.removed.iter().any(|tag| tags.contains(tag))
+    }
+
+    /// Get the change that reverts this one
+    pub fn invert(self) -> Self {
+        Self {
+            item_uri: self.item_uri,
+            added: self.removed,
+            removed: self.added,
+        }
+    }
+}
+
+/// A document in the `undoEntries` collection. This records what a single
+/// mutation changed, so that it can be reverted.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct UndoEntryDocument {
+    #[serde(rename = "_id")]
+    pub id: ObjectId,
+    pub user_id: UserId,
+    pub expires_at: bson::DateTime,
+    pub item_changes: Vec<ItemTagChange>,
+    /// Snapshots of tag metadata from before the mutation, for any tags whose
+    /// metadata was modified or deleted
+    pub tag_metadata: Vec<TagDocument>,
+}
+
+/// A document in the `tagEvents` collection. Each document records a single
+/// tag being added to or removed from a single item.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TagEventDocument {
+    pub user_id: UserId,
+    pub item_uri: ItemUri,
+    pub tag: Tag,
+    pub action: TagAction,
+    pub timestamp: bson::DateTime,
+}
+
+/// A document in the `smartTags` collection. This defines a single smart tag,
+/// which applies to any item that satisfies every one of its conditions. Each
+/// document is uniquely identified by the combination of user ID and tag.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SmartTagDocument {
+    pub user_id: UserId,
+    pub tag: Tag,
+    pub conditions: Vec<SmartTagCondition>,
+}
+
+/// The single document in the `migrations` collection. The document's ID is
+/// fixed, so it's left out here.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct MigrationStateDocument {
+    /// The last [SCHEMA_VERSION] that was migrated to
+    pub schema_version: u32,
+    /// The rules that tags were last normalized under
+    pub tag_rules: Option<TagRules>,
+}
+
+/// The number of items that a tag is applied to, generated by a `$group`
+/// aggregation
+#[derive(Clone, Debug, Deserialize)]
+pub struct TagUsageDocument {
+    pub tag: Tag,
+    pub count: u64,
+}
+
+/// A pair of tags that are applied to some of the same items, generated by
+/// [TaggedItemsCollection::find_tag_cooccurrences]
+#[derive(Clone, Debug, Deserialize)]
+pub struct TagCooccurrenceDocument {
+    pub tag_a: Tag,
+    pub tag_b: Tag,
+    /// The number of items that have both tags
+    pub count: u64,
+    /// Jaccard similarity of the two tags' item sets, from 0 to 1
+    pub jaccard: f64,
+}
+
+/// The number of items of a particular type that a tag is applied to,
+/// generated by a `$group` aggregation
+#[derive(Clone, Debug, Deserialize)]
+pub struct TagItemCountDocument {
+    pub tag: Tag,
+    pub item_type: ItemType,
+    pub count: u64,
+}
+
+/// A summary of tag information, generated by an unwind query.
+#[derive(Clone, Debug, Deserialize)]
+struct TagSummaryDocument {
+    tag: Tag,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tags(tags: &[&str]) -> Vec<Tag> {
+        tags.iter().map(|tag| Tag::new((*tag).into())).collect()
+    }
+
+    /// Apply a change to a list of tags, the same way the DB does
+    fn apply(before: &[Tag], change: &ItemTagChange) -> Vec<Tag> {
+        before
+            .iter()
+            .filter(|tag| !change.removed.contains(tag))
+            .chain(change.added.iter().filter(|tag| !before.contains(tag)))
+            .cloned()
+            .sorted()
+            .collect()
+    }
+
+    fn assert_round_trip(before: &[&str], after: &[&str]) -> ItemTagChange {
+        let (before, after) = (tags(before), tags(after));
+        let uri: ItemUri = "spotify:track:abc".parse().unwrap();
+        let change = ItemTagChange::diff(uri, &before, &after);
+        assert_eq!(apply(&before, &change), after);
+        assert!(change.is_current(&after));
+        let revert = change.clone().invert();
+        assert_eq!(apply(&after, &revert), before);
+        assert!(revert.is_current(&before));
+        change
+    }
+
+    #[test]
+    fn test_diff_added() {
+        let change = assert_round_trip(&["a"], &["a", "b", "c"]);
+        assert_eq!(change.added, tags(&["b", "c"]));
+        assert_eq!(change.removed, tags(&[]));
+    }
+
+    #[test]
+    fn test_diff_removed() {
+        let change = assert_round_trip(&["a", "b", "c"], &["b"]);
+        assert_eq!(change.added, tags(&[]));
+        assert_eq!(change.removed, tags(&["a", "c"]));
+    }
+
+    #[test]
+    fn test_diff_mixed() {
+        let change = assert_round_trip(&["a", "b"], &["b", "c"]);
+        assert_eq!(change.added, tags(&["c"]));
+        assert_eq!(change.removed, tags(&["a"]));
+        let change = assert_round_trip(&[], &["a"]);
+        assert_eq!(change.added, tags(&["a"]));
+        let change = assert_round_trip(&["a"], &[]);
+        assert_eq!(change.removed, tags(&["a"]));
+    }
+
+    #[test]
+    fn test_diff_no_op() {
+        let change = assert_round_trip(&["a", "b"], &["a", "b"]);
+        assert!(change.is_empty());
+        assert!(change.clone().invert().is_empty());
+        assert!(assert_round_trip(&[], &[]).is_empty());
+    }
+
+    #[test]
+    fn test_is_current() {
+        let uri: ItemUri = "spotify:track:abc".parse().unwrap();
+        let change =
+            ItemTagChange::diff(uri, &tags(&["a", "b"]), &tags(&["b", "c"]));
+        assert!(change.is_current(&tags(&["b", "c"])));
+        // Tags that the change didn't touch don't matter
+        assert!(change.is_current(&tags(&["c", "d"])));
+        // An added tag has been removed since
+        assert!(!change.is_current(&tags(&["b"])));
+        // A removed tag has been added back since
+        assert!(!change.is_current(&tags(&["a", "b", "c"])));
+        // The item's tags have all been removed since
+        assert!(!change.is_current(&[]));
+    }
 }
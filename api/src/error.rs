@@ -1,4 +1,4 @@
-use crate::spotify::SpotifyItemType;
+use crate::provider::{ItemType, Provider};
 use async_graphql::{ErrorExtensions, FieldError};
 use log::{log, Level};
 use mongodb::bson;
@@ -107,10 +107,26 @@ pub enum ApiError {
     /// Tried to tag an item of an unsupported type
     #[error("Tagging not supported for item of type: {item_type}")]
     UnsupportedItemType {
-        item_type: SpotifyItemType,
+        item_type: ItemType,
         backtrace: Backtrace,
     },
 
+    /// Item belongs to a provider that isn't available
+    #[error("No provider available for items from: {provider}")]
+    UnsupportedProvider {
+        provider: Provider,
+        backtrace: Backtrace,
+    },
+
+    /// Undo token doesn't exist, has expired, or belongs to another user
+    #[error("Undo token is invalid or has expired")]
+    InvalidUndoToken { backtrace: Backtrace },
+
+    /// The tags touched by an undoable change have been modified since, so
+    /// reverting it would clobber newer changes
+    #[error("Change can no longer be undone, because the tags have changed")]
+    UndoConflict { backtrace: Backtrace },
+
     /// Catch-all error, should have a descriptive message
     #[error("Unknown error: {message}")]
     Unknown {
@@ -130,7 +146,10 @@ impl ApiError {
         match self {
             // 400
             Self::UnsupportedItemType { .. }
-            | Self::Parse { .. } => Status::BadRequest,
+            | Self::UnsupportedProvider { .. }
+            | Self::Parse { .. }
+            | Self::InvalidUndoToken { .. }
+            | Self::UndoConflict { .. } => Status::BadRequest,
 
             // 401
             Self::Unauthenticated { .. }
@@ -3,9 +3,10 @@
 
 use crate::error::ParseError;
 use async_graphql::{scalar, Object};
-use derive_more::Display;
+use derive_more::{Display, From, Into};
+use mongodb::bson;
 use serde::{Deserialize, Serialize};
-use std::str::FromStr;
+use std::{convert::TryFrom, fmt, str::FromStr};
 
 /// An identifier determining where in a paginated sequence of data we are. A
 /// cursor is just some offset value converted into a string, so this struct
@@ -59,6 +60,64 @@ impl FromStr for Cursor {
     }
 }
 
+/// A point in time. This is stored in the DB as a native BSON date, and
+/// exposed in the API as an RFC 3339 string.
+#[derive(
+    Copy,
+    Clone,
+    Debug,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    From,
+    Into,
+    Serialize,
+    Deserialize,
+)]
+#[serde(try_from = "String", into = "String")]
+pub struct Timestamp(bson::DateTime);
+
+scalar!(Timestamp);
+
+impl fmt::Display for Timestamp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Formatting can only fail for dates way outside the range we'll ever
+        // be dealing with, so fall back to bson's formatting in that case
+        match self.0.try_to_rfc3339_string() {
+            Ok(formatted) => write!(f, "{}", formatted),
+            Err(_) => write!(f, "{}", self.0),
+        }
+    }
+}
+
+impl FromStr for Timestamp {
+    type Err = ParseError;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        bson::DateTime::parse_rfc3339_str(value)
+            .map(Self)
+            .map_err(|_| ParseError {
+                message: "Invalid RFC 3339 timestamp".into(),
+                value: value.into(),
+            })
+    }
+}
+
+// These two impls needed for serde
+impl From<Timestamp> for String {
+    fn from(timestamp: Timestamp) -> Self {
+        timestamp.to_string()
+    }
+}
+impl TryFrom<String> for Timestamp {
+    type Error = <Timestamp as FromStr>::Err;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
 /// GQL type to display information about a page of data. See the Relay
 /// Connections spec: https://facebook.github.io/relay/graphql/connections.htm#sec-undefined.PageInfo
 #[derive(Clone, Debug)]
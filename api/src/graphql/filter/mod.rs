@@ -0,0 +1,119 @@
+//! Types for filtering tagged items by boolean expressions over their tags.
+//! Filters can be given either as structured GraphQL input, or as a string
+//! query (see the [parse] module for the syntax).
+
+mod parse;
+
+use crate::{
+    error::ParseError,
+    graphql::{RatingFilter, Tag},
+    provider::{ItemType, ItemUri},
+};
+use async_graphql::OneofObject;
+use std::convert::TryFrom;
+
+/// The deepest that a filter expression can be nested. Filters are parsed,
+/// converted and compiled recursively, so without a limit a deeply nested
+/// filter could overflow the stack and take down the whole server.
+pub const MAX_FILTER_DEPTH: usize = 64;
+
+/// A boolean expression over tags, which can be used to filter tagged items.
+/// This is the internal version of the expression, which gets compiled into a
+/// DB query by [crate::db::TaggedItemsCollection]. It can be built from
+/// different external representations, e.g. [TagFilterInput] or a string
+/// query (via `FromStr`). Either way, it's never nested deeper than
+/// [MAX_FILTER_DEPTH].
+#[derive(Clone, Debug, PartialEq)]
+pub enum TagFilter {
+    /// Match items that have this tag
+    Tag(Tag),
+    /// Match items of this type. This isn't really about tags, but it's useful
+    /// to be able to narrow down results by type within the same expression.
+    ItemType(ItemType),
+    /// Match items whose note contains this text (case-insensitive)
+    NoteContains(String),
+    /// Match items rated within this range
+    Rating(RatingFilter),
+    /// Match exactly these items. This can't be given externally; it's used
+    /// to splice in the matches for smart tags, which aren't stored in the DB.
+    Items(Vec<ItemUri>),
+    /// Match items that match every sub-filter. An empty list matches
+    /// everything.
+    And(Vec<TagFilter>),
+    /// Match items that match at least one sub-filter. An empty list matches
+    /// nothing.
+    Or(Vec<TagFilter>),
+    /// Match items that don't match the sub-filter
+    Not(Box<TagFilter>),
+}
+
+/// A boolean expression over tags. Exactly one field must be given, and
+/// expressions can be nested up to 64 levels deep.
+#[derive(Clone, Debug, OneofObject)]
+pub enum TagFilterInput {
+    /// Match items that have this tag
+    Tag(Tag),
+    /// Match items rated within this range
+    Rating(RatingFilter),
+    /// Match items that match every sub-filter
+    And(Vec<TagFilterInput>),
+    /// Match items that match at least one sub-filter
+    Or(Vec<TagFilterInput>),
+    /// Match items that don't match the sub-filter
+    Not(Box<TagFilterInput>),
+}
+
+impl TagFilter {
+    /// Get how many levels deep this filter is nested. A filter with no
+    /// sub-filters is 1 level deep.
+    pub fn depth(&self) -> usize {
+        match self {
+            Self::And(filters) | Self::Or(filters) => {
+                1 + filters.iter().map(Self::depth).max().unwrap_or(0)
+            }
+            Self::Not(filter) => 1 + filter.depth(),
+            _ => 1,
+        }
+    }
+
+    /// Convert a GraphQL input into a filter, where the input is nested
+    /// `depth` levels deep in the full expression
+    fn from_input(
+        input: TagFilterInput,
+        depth: usize,
+    ) -> Result<Self, ParseError> {
+        if depth >= MAX_FILTER_DEPTH {
+            return Err(ParseError {
+                message: format!(
+                    "Cannot be nested more than {} levels deep",
+                    MAX_FILTER_DEPTH
+                ),
+                value: "filter".into(),
+            });
+        }
+        let from_inputs = |filters: Vec<TagFilterInput>| {
+            filters
+                .into_iter()
+                .map(|filter| Self::from_input(filter, depth + 1))
+                .collect::<Result<Vec<_>, _>>()
+        };
+
+        Ok(match input {
+            TagFilterInput::Tag(tag) => Self::Tag(tag),
+            TagFilterInput::Rating(rating) => Self::Rating(rating),
+            TagFilterInput::And(filters) => Self::And(from_inputs(filters)?),
+            TagFilterInput::Or(filters) => Self::Or(from_inputs(filters)?),
+            TagFilterInput::Not(filter) => {
+                Self::Not(Box::new(Self::from_input(*filter, depth + 1)?))
+            }
+        })
+    }
+}
+
+impl TryFrom<TagFilterInput> for TagFilter {
+    type Error = ParseError;
+
+    fn try_from(input: TagFilterInput) -> Result<Self, Self::Error> {
+        Self::from_input(input, 0)
+    }
+}
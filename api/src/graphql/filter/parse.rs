@@ -0,0 +1,547 @@
+//! A parser for string tag queries, so that power users can type a filter
+//! into a single search box. For example:
+//!
+//! ```text
+//! tag:chill -tag:vocal (tag:jazz | tag:soul) type:track
+//! ```
+//!
+//! The syntax is:
+//! - `tag:<tag>` matches items with a tag, `type:<type>` matches items of a
+//!   type (track, album, etc.), `note:<text>` matches items whose note contains
+//!   some text
+//! - Values with spaces or special characters can be quoted: `tag:"lo fi"`
+//! - Terms separated by whitespace are ANDed together
+//! - `|` ORs terms together, and binds looser than AND
+//! - `-` negates the following term
+//! - Parentheses group terms
+//!
+//! Groups and negations can be nested up to [MAX_FILTER_DEPTH] levels deep,
+//! and the resulting filter can't be nested more than [MAX_FILTER_DEPTH]
+//! levels deep either. These differ: a group around a single term adds no
+//! level to the filter, while a group of ORed ANDs adds two.
+
+use crate::{
+    error::ParseError,
+    graphql::{Tag, TagFilter, MAX_FILTER_DEPTH},
+    provider::ItemType,
+};
+use derive_more::Display;
+use std::{fmt::Display, str::FromStr};
+
+/// A single lexical token in a query
+#[derive(Clone, Debug, Display, PartialEq)]
+enum Token {
+    #[display(fmt = "`(`")]
+    OpenParen,
+    #[display(fmt = "`)`")]
+    CloseParen,
+    #[display(fmt = "`|`")]
+    Pipe,
+    #[display(fmt = "`-`")]
+    Minus,
+    /// A `key:value` pair
+    #[display(fmt = "`{}:{}`", key, value)]
+    Term { key: String, value: String },
+}
+
+/// A token, plus the position where it starts in the query. Positions are
+/// counted in characters, starting at 0.
+#[derive(Clone, Debug)]
+struct Spanned {
+    token: Token,
+    position: usize,
+}
+
+impl FromStr for TagFilter {
+    type Err = ParseError;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let tokens = tokenize(value)?;
+        if tokens.is_empty() {
+            return Err(error(value, 0, "Query cannot be empty"));
+        }
+
+        let mut parser = Parser {
+            query: value,
+            tokens,
+            index: 0,
+            depth: 0,
+        };
+        let filter = parser.parse_or()?;
+        // Anything left over means there was an unmatched closing paren
+        if let Some(Spanned { token, position }) = parser.advance() {
+            return Err(error(
+                value,
+                position,
+                format!("Unexpected {}", token),
+            ));
+        }
+        if filter.depth() > MAX_FILTER_DEPTH {
+            return Err(nesting_error(value, 0));
+        }
+        Ok(filter)
+    }
+}
+
+/// Build a parse error for a query, pointing at a particular position
+fn error(query: &str, position: usize, message: impl Display) -> ParseError {
+    ParseError {
+        message: format!("{} at position {}", message, position),
+        value: query.into(),
+    }
+}
+
+/// Build the error for a query that's nested too deeply
+fn nesting_error(query: &str, position: usize) -> ParseError {
+    error(
+        query,
+        position,
+        format!(
+            "Query cannot be nested more than {} levels deep",
+            MAX_FILTER_DEPTH
+        ),
+    )
+}
+
+/// Does this character end a bare (unquoted) word?
+fn is_delimiter(c: char) -> bool {
+    c.is_whitespace() || "()|".contains(c)
+}
+
+/// Split a query into tokens
+fn tokenize(query: &str) -> Result<Vec<Spanned>, ParseError> {
+    let chars: Vec<char> = query.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+
+    while i < chars.len() {
+        let position = i;
+        let token = match chars[i] {
+            c if c.is_whitespace() => {
+                i += 1;
+                continue;
+            }
+            '(' => {
+                i += 1;
+                Token::OpenParen
+            }
+            ')' => {
+                i += 1;
+                Token::CloseParen
+            }
+            '|' => {
+                i += 1;
+                Token::Pipe
+            }
+            '-' => {
+                i += 1;
+                Token::Minus
+            }
+            _ => {
+                // The key runs up to the colon
+                while i < chars.len()
+                    && chars[i] != ':'
+                    && !is_delimiter(chars[i])
+                {
+                    i += 1;
+                }
+                if chars.get(i) != Some(&':') {
+                    return Err(error(query, position, "Expected `key:value`"));
+                }
+                let key: String = chars[position..i].iter().collect();
+                i += 1; // Skip the colon
+
+                let value_position = i;
+                let value: String = if chars.get(i) == Some(&'"') {
+                    // Quoted value runs up to the closing quote
+                    i += 1;
+                    let start = i;
+                    while i < chars.len() && chars[i] != '"' {
+                        i += 1;
+                    }
+                    if i >= chars.len() {
+                        return Err(error(
+                            query,
+                            value_position,
+                            "Unterminated quote",
+                        ));
+                    }
+                    let value = chars[start..i].iter().collect();
+                    i += 1; // Skip the closing quote
+                    value
+                } else {
+                    while i < chars.len() && !is_delimiter(chars[i]) {
+                        i += 1;
+                    }
+                    chars[value_position..i].iter().collect()
+                };
+                if value.is_empty() {
+                    return Err(error(
+                        query,
+                        value_position,
+                        format!("Missing value for `{}`", key),
+                    ));
+                }
+
+                Token::Term { key, value }
+            }
+        };
+        tokens.push(Spanned { token, position });
+    }
+
+    Ok(tokens)
+}
+
+/// A recursive descent parser over a list of tokens. The grammar, from
+/// loosest to tightest binding, is:
+///
+/// ```text
+/// or    = and ("|" and)*
+/// and   = unary unary*
+/// unary = "-" unary | atom
+/// atom  = "(" or ")" | term
+/// ```
+struct Parser<'a> {
+    query: &'a str,
+    tokens: Vec<Spanned>,
+    index: usize,
+    /// How many groups/negations we're currently nested inside. This bounds
+    /// the parser's own recursion; the depth of the built filter is checked
+    /// separately once parsing is done.
+    depth: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.index).map(|spanned| &spanned.token)
+    }
+
+    fn advance(&mut self) -> Option<Spanned> {
+        let spanned = self.tokens.get(self.index).cloned();
+        self.index += 1;
+        spanned
+    }
+
+    /// Get the position of the next token, or the end of the query if we're
+    /// out of tokens
+    fn position(&self) -> usize {
+        match self.tokens.get(self.index) {
+            Some(spanned) => spanned.position,
+            None => self.query.chars().count(),
+        }
+    }
+
+    /// Go one level deeper into a group or negation starting at `position`.
+    /// Errors if that's deeper than we allow, so that a malicious query can't
+    /// blow the stack. Every call should be paired with a decrement once the
+    /// nested expression has been parsed.
+    fn descend(&mut self, position: usize) -> Result<(), ParseError> {
+        self.depth += 1;
+        if self.depth > MAX_FILTER_DEPTH {
+            Err(nesting_error(self.query, position))
+        } else {
+            Ok(())
+        }
+    }
+
+    fn parse_or(&mut self) -> Result<TagFilter, ParseError> {
+        let mut filters = vec![self.parse_and()?];
+        while self.peek() == Some(&Token::Pipe) {
+            self.index += 1;
+            filters.push(self.parse_and()?);
+        }
+        Ok(collapse(filters, TagFilter::Or))
+    }
+
+    fn parse_and(&mut self) -> Result<TagFilter, ParseError> {
+        let mut filters = vec![self.parse_unary()?];
+        // Keep going until we hit something that ends this group
+        while !matches!(
+            self.peek(),
+            None | Some(Token::Pipe) | Some(Token::CloseParen)
+        ) {
+            filters.push(self.parse_unary()?);
+        }
+        Ok(collapse(filters, TagFilter::And))
+    }
+
+    fn parse_unary(&mut self) -> Result<TagFilter, ParseError> {
+        if self.peek() == Some(&Token::Minus) {
+            self.descend(self.position())?;
+            self.index += 1;
+            let filter = self.parse_unary()?;
+            self.depth -= 1;
+            Ok(TagFilter::Not(Box::new(filter)))
+        } else {
+            self.parse_atom()
+        }
+    }
+
+    fn parse_atom(&mut self) -> Result<TagFilter, ParseError> {
+        let end_position = self.position();
+        match self.advance() {
+            Some(Spanned {
+                token: Token::OpenParen,
+                position,
+            }) => {
+                self.descend(position)?;
+                let filter = self.parse_or()?;
+                self.depth -= 1;
+                match self.advance() {
+                    Some(Spanned {
+                        token: Token::CloseParen,
+                        ..
+                    }) => Ok(filter),
+                    _ => Err(error(self.query, position, "Unclosed `(`")),
+                }
+            }
+            Some(Spanned {
+                token: Token::Term { key, value },
+                position,
+            }) => self.parse_term(position, &key, &value),
+            Some(Spanned { token, position }) => Err(error(
+                self.query,
+                position,
+                format!("Unexpected {}", token),
+            )),
+            None => {
+                Err(error(self.query, end_position, "Unexpected end of query"))
+            }
+        }
+    }
+
+    fn parse_term(
+        &self,
+        position: usize,
+        key: &str,
+        value: &str,
+    ) -> Result<TagFilter, ParseError> {
+        match key {
+            "tag" => value
+                .parse::<Tag>()
+                .map(TagFilter::Tag)
+                .map_err(|err| error(self.query, position, err.message)),
+            "type" => value
+                .parse::<ItemType>()
+                .map(TagFilter::ItemType)
+                .map_err(|err| error(self.query, position, err.message)),
+            "note" => Ok(TagFilter::NoteContains(value.into())),
+            _ => Err(error(
+                self.query,
+                position,
+                format!("Unknown filter `{}`", key),
+            )),
+        }
+    }
+}
+
+/// Wrap a list of filters in a combinator, unless there's only one filter, in
+/// which case it's returned on its own
+fn collapse(
+    mut filters: Vec<TagFilter>,
+    combine: fn(Vec<TagFilter>) -> TagFilter,
+) -> TagFilter {
+    if filters.len() == 1 {
+        filters.remove(0)
+    } else {
+        combine(filters)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(query: &str) -> Result<TagFilter, String> {
+        query.parse().map_err(|err: ParseError| err.message)
+    }
+
+    fn tag(tag: &str) -> TagFilter {
+        TagFilter::Tag(Tag::new(tag.into()))
+    }
+
+    #[test]
+    fn test_terms() {
+        assert_eq!(parse("tag:chill"), Ok(tag("chill")));
+        assert_eq!(parse("tag:genre/jazz"), Ok(tag("genre/jazz")));
+        assert_eq!(
+            parse("type:album"),
+            Ok(TagFilter::ItemType(ItemType::Album))
+        );
+        assert_eq!(
+            parse("note:great"),
+            Ok(TagFilter::NoteContains("great".into()))
+        );
+    }
+
+    #[test]
+    fn test_quoting() {
+        assert_eq!(parse(r#"tag:"lo fi""#), Ok(tag("lo fi")));
+        // Special characters lose their meaning inside quotes
+        assert_eq!(
+            parse(r#"note:"a (b) | -c""#),
+            Ok(TagFilter::NoteContains("a (b) | -c".into()))
+        );
+        // Quotes end the value, so another term can follow immediately
+        assert_eq!(
+            parse(r#"tag:"a b"tag:c"#),
+            Ok(TagFilter::And(vec![tag("a b"), tag("c")]))
+        );
+    }
+
+    #[test]
+    fn test_precedence() {
+        // AND binds tighter than OR
+        assert_eq!(
+            parse("tag:a tag:b | tag:c"),
+            Ok(TagFilter::Or(vec![
+                TagFilter::And(vec![tag("a"), tag("b")]),
+                tag("c"),
+            ]))
+        );
+        // Negation binds tighter than AND
+        assert_eq!(
+            parse("-tag:a tag:b"),
+            Ok(TagFilter::And(vec![
+                TagFilter::Not(Box::new(tag("a"))),
+                tag("b"),
+            ]))
+        );
+        // Parentheses override everything
+        assert_eq!(
+            parse("tag:a (tag:b | tag:c)"),
+            Ok(TagFilter::And(vec![
+                tag("a"),
+                TagFilter::Or(vec![tag("b"), tag("c")]),
+            ]))
+        );
+        assert_eq!(
+            parse("-(tag:a | tag:b)"),
+            Ok(TagFilter::Not(Box::new(TagFilter::Or(vec![
+                tag("a"),
+                tag("b"),
+            ]))))
+        );
+        assert_eq!(parse("((tag:a))"), Ok(tag("a")));
+        assert_eq!(
+            parse("--tag:a"),
+            Ok(TagFilter::Not(Box::new(TagFilter::Not(Box::new(tag("a"))))))
+        );
+    }
+
+    #[test]
+    fn test_errors() {
+        assert_eq!(
+            parse(""),
+            Err("Query cannot be empty at position 0".into())
+        );
+        assert_eq!(
+            parse("   "),
+            Err("Query cannot be empty at position 0".into())
+        );
+        assert_eq!(
+            parse("tag:a chill"),
+            Err("Expected `key:value` at position 6".into())
+        );
+        assert_eq!(
+            parse("tag:a tag:"),
+            Err("Missing value for `tag` at position 10".into())
+        );
+        assert_eq!(
+            parse(r#"tag:a tag:"lo fi"#),
+            Err("Unterminated quote at position 10".into())
+        );
+        assert_eq!(
+            parse("tag:a bad:x"),
+            Err("Unknown filter `bad` at position 6".into())
+        );
+        assert_eq!(
+            parse("type:song"),
+            Err("Unknown item type at position 0".into())
+        );
+        assert_eq!(
+            parse("tag:a )"),
+            Err("Unexpected `)` at position 6".into())
+        );
+        assert_eq!(parse(" (tag:a"), Err("Unclosed `(` at position 1".into()));
+        assert_eq!(
+            parse("tag:a |"),
+            Err("Unexpected end of query at position 7".into())
+        );
+        assert_eq!(
+            parse("tag:a | | tag:b"),
+            Err("Unexpected `|` at position 8".into())
+        );
+    }
+
+    #[test]
+    fn test_max_depth() {
+        let nested = |depth: usize| {
+            format!("{}tag:a{}", "(".repeat(depth), ")".repeat(depth))
+        };
+        assert_eq!(parse(&nested(MAX_FILTER_DEPTH)), Ok(tag("a")));
+        assert_eq!(
+            parse(&nested(MAX_FILTER_DEPTH + 1)),
+            Err(format!(
+                "Query cannot be nested more than {} levels deep at position {}",
+                MAX_FILTER_DEPTH, MAX_FILTER_DEPTH
+            ))
+        );
+        // Each negation is a level in the filter, on top of the tag itself
+        let negated = |depth: usize| format!("{}tag:a", "-".repeat(depth - 1));
+        assert_eq!(
+            parse(&negated(MAX_FILTER_DEPTH)).unwrap().depth(),
+            MAX_FILTER_DEPTH
+        );
+        assert_eq!(
+            parse(&negated(MAX_FILTER_DEPTH + 1)),
+            Err(format!(
+                "Query cannot be nested more than {} levels deep at position 0",
+                MAX_FILTER_DEPTH
+            ))
+        );
+        // Groups around a single term add no levels, but they still count
+        // against the parser's limit
+        assert_eq!(
+            parse(&format!("-{}", nested(MAX_FILTER_DEPTH - 1))),
+            Ok(TagFilter::Not(Box::new(tag("a"))))
+        );
+        // Way past the limit, this would blow the stack if it weren't caught
+        assert!(parse(&nested(100_000)).is_err());
+        assert!(parse(&"-".repeat(100_000)).is_err());
+    }
+
+    #[test]
+    fn test_max_filter_depth() {
+        // Each group here adds a level to the filter, but the parser only
+        // sees one level of nesting per group, so this checks the depth of
+        // the built filter rather than the parser's count
+        fn nested(depth: usize) -> String {
+            if depth == 1 {
+                "tag:a".into()
+            } else {
+                format!("tag:a ({})", nested(depth - 1))
+            }
+        }
+        assert_eq!(
+            parse(&nested(3)),
+            Ok(TagFilter::And(vec![
+                tag("a"),
+                TagFilter::And(vec![tag("a"), tag("a")]),
+            ]))
+        );
+        assert_eq!(
+            parse(&nested(MAX_FILTER_DEPTH)).unwrap().depth(),
+            MAX_FILTER_DEPTH
+        );
+        assert_eq!(
+            parse(&nested(MAX_FILTER_DEPTH + 1)),
+            Err(format!(
+                "Query cannot be nested more than {} levels deep at position 0",
+                MAX_FILTER_DEPTH
+            ))
+        );
+        // ORed ANDs add two levels to the filter for each group
+        assert_eq!(parse("(tag:a tag:b | tag:c) tag:d").unwrap().depth(), 4);
+    }
+}
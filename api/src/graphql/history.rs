@@ -0,0 +1,120 @@
+//! Types for the tag audit log. Every time a tag is added to or removed from
+//! an item, an event is recorded, so users can see how an item or a tag has
+//! changed over time.
+
+use crate::{
+    db::TagEventDocument,
+    graphql::{
+        internal::GenericEdge, Cursor, PageInfo, RequestContext, Tag, Timestamp,
+    },
+    provider::ItemUri,
+};
+use async_graphql::{Context, Enum, FieldResult, Object, SimpleObject};
+use futures::TryStreamExt;
+use serde::{Deserialize, Serialize};
+use std::convert::TryInto;
+
+/// A change that was made to the tags on an item
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Enum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TagAction {
+    /// The tag was applied to the item
+    Add,
+    /// The tag was removed from the item
+    Remove,
+}
+
+/// A single entry in the tag audit log
+#[derive(Clone, Debug, SimpleObject)]
+pub struct TagEvent {
+    pub item_uri: ItemUri,
+    pub tag: Tag,
+    pub action: TagAction,
+    pub timestamp: Timestamp,
+}
+
+impl From<TagEventDocument> for TagEvent {
+    fn from(doc: TagEventDocument) -> Self {
+        Self {
+            item_uri: doc.item_uri,
+            tag: doc.tag,
+            action: doc.action,
+            timestamp: doc.timestamp.into(),
+        }
+    }
+}
+
+pub type TagEventEdge = GenericEdge<TagEvent>;
+
+#[Object]
+impl TagEventEdge {
+    async fn node(&self) -> &TagEvent {
+        &self.node
+    }
+
+    async fn cursor(&self) -> &Cursor {
+        &self.cursor
+    }
+}
+
+/// "Connection" is a concept from Relay. Read more: https://graphql.org/learn/pagination/
+/// A page of tag events, newest first, optionally filtered by item and/or
+/// tag. Events are loaded lazily from the DB, as needed.
+#[derive(Clone, Debug)]
+pub struct TagEventConnection {
+    pub item_uri: Option<ItemUri>,
+    pub tag: Option<Tag>,
+    pub offset: usize,
+    pub limit: usize,
+}
+
+#[Object]
+impl TagEventConnection {
+    async fn total_count(&self, context: &Context<'_>) -> FieldResult<usize> {
+        let context = context.data::<RequestContext>()?;
+        let total_count = context
+            .db_handler
+            .collection_tag_events()
+            .count_events(
+                &context.user_id,
+                self.item_uri.as_ref(),
+                self.tag.as_ref(),
+            )
+            .await?;
+        Ok(total_count.try_into()?)
+    }
+
+    async fn page_info(&self, context: &Context<'_>) -> FieldResult<PageInfo> {
+        // This will hit the DB to count matching events
+        let total_count = self.total_count(context).await?;
+        let remaining = total_count.saturating_sub(self.offset);
+        Ok(PageInfo {
+            offset: self.offset,
+            page_len: remaining.min(self.limit),
+            has_previous_page: self.offset > 0,
+            has_next_page: remaining > self.limit,
+        })
+    }
+
+    async fn edges(
+        &self,
+        context: &Context<'_>,
+    ) -> FieldResult<Vec<TagEventEdge>> {
+        let context = context.data::<RequestContext>()?;
+        let events: Vec<TagEvent> = context
+            .db_handler
+            .collection_tag_events()
+            .find_events(
+                &context.user_id,
+                self.item_uri.as_ref(),
+                self.tag.as_ref(),
+                self.offset.try_into()?,
+                self.limit.try_into()?,
+            )
+            .await?
+            .map_ok(TagEvent::from)
+            .try_collect()
+            .await?;
+        Ok(GenericEdge::from_nodes(events.into_iter(), self.offset))
+    }
+}
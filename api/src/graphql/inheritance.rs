@@ -0,0 +1,128 @@
+//! Tag inheritance, where tags on an artist also apply to the artist's albums,
+//! and tags on an album or artist also apply to their tracks. Inheritance is
+//! opt-in, and only happens at query time. Inherited tags are never stored on
+//! items, so removing a tag from an album removes it from its tracks too.
+
+use crate::{
+    db::TaggedItemDocument,
+    error::ApiResult,
+    graphql::{RequestContext, Tag},
+    provider::{ItemType, ItemUri},
+    spotify::Item,
+};
+use futures::{stream, StreamExt, TryStreamExt};
+use itertools::Itertools;
+use std::{collections::HashMap, iter};
+
+/// Maximum number of artists to fetch albums for at once
+const MAX_CONCURRENT_ARTISTS: usize = 5;
+
+/// Get the tags that an item inherits from its album and/or artists. Each tag
+/// is mapped to the items it's inherited from. Tags in `direct` are already
+/// applied to the item directly, so they're left out.
+pub async fn find_inherited_tags(
+    context: &RequestContext,
+    item: &Item,
+    direct: &[Tag],
+) -> ApiResult<HashMap<Tag, Vec<ItemUri>>> {
+    let sources: Vec<ItemUri> = match item {
+        Item::Track(track) => iter::once(&track.album.uri)
+            .chain(track.artists.iter().map(|artist| &artist.uri))
+            .cloned()
+            .collect(),
+        Item::Album(album) => album
+            .artists
+            .iter()
+            .map(|artist| artist.uri.clone())
+            .collect(),
+        Item::Artist(_)
+        | Item::Playlist(_)
+        | Item::Show(_)
+        | Item::Episode(_) => Vec::new(),
+    };
+    if sources.is_empty() {
+        return Ok(HashMap::new());
+    }
+
+    let mut inherited: HashMap<Tag, Vec<ItemUri>> = HashMap::new();
+    let mut cursor = context
+        .db_handler
+        .collection_tagged_items()
+        .find_by_items(&context.user_id, &sources)
+        .await?;
+    while let Some(doc) = cursor.try_next().await? {
+        for tag in doc.tags {
+            if !direct.contains(&tag) {
+                inherited.entry(tag).or_default().push(doc.uri.clone());
+            }
+        }
+    }
+    Ok(inherited)
+}
+
+/// Find every item that inherits a tag from an album or artist. If
+/// `include_descendants` is set, items that inherit any tag nested under the
+/// given one are included too. Some of these items may have the tag applied
+/// directly as well, so the caller is responsible for deduping.
+///
+/// This has to go to Spotify to find each album's tracks and each artist's
+/// albums, so it gets slower the more albums and artists have the tag. Only
+/// the first 50 albums per artist and 50 tracks per album are included.
+pub async fn find_inheriting_items(
+    context: &RequestContext,
+    tag: &Tag,
+    include_descendants: bool,
+) -> ApiResult<Vec<ItemUri>> {
+    let docs: Vec<TaggedItemDocument> = context
+        .db_handler
+        .collection_tagged_items()
+        .find_by_tag(&context.user_id, tag, include_descendants, None, &[])
+        .await?
+        .try_collect()
+        .await?;
+    let mut album_uris: Vec<&ItemUri> = Vec::new();
+    let mut artist_uris: Vec<&ItemUri> = Vec::new();
+    for doc in &docs {
+        match doc.uri.item_type() {
+            ItemType::Album => album_uris.push(&doc.uri),
+            ItemType::Artist => artist_uris.push(&doc.uri),
+            // Nothing else passes its tags on to anything
+            _ => {}
+        }
+    }
+
+    // Artists pass the tag on to their albums. Each artist is a separate
+    // request, so don't fire them all off at once.
+    let artist_albums: Vec<_> =
+        stream::iter(artist_uris.iter().map(|artist_uri| async move {
+            context
+                .providers
+                .for_uri(artist_uri)?
+                .get_artist_albums(artist_uri)
+                .await
+        }))
+        .buffer_unordered(MAX_CONCURRENT_ARTISTS)
+        .try_collect()
+        .await?;
+    let albums: Vec<_> = artist_albums
+        .into_iter()
+        .flat_map(|response| response.items)
+        .collect();
+    album_uris.extend(albums.iter().map(|album| &album.uri));
+    let mut uris: Vec<ItemUri> =
+        albums.iter().map(|album| album.uri.clone()).collect();
+
+    // Then every album (tagged directly or via its artist) passes the tag on
+    // to its tracks
+    let album_uris: Vec<&ItemUri> = album_uris.into_iter().unique().collect();
+    if !album_uris.is_empty() {
+        let track_uris = context
+            .providers
+            .call_grouped(album_uris, |provider, uris| async move {
+                provider.get_album_track_uris(&uris).await
+            })
+            .await?;
+        uris.extend(track_uris);
+    }
+    Ok(uris)
+}
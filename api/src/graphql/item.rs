@@ -1,15 +1,70 @@
-use std::convert::TryInto;
+use std::{
+    borrow::Cow,
+    cmp::Reverse,
+    collections::{BTreeSet, HashMap, HashSet},
+    convert::TryInto,
+    sync::Arc,
+};
 
 use crate::{
+    db::TaggedItemDocument,
+    error::ApiResult,
     graphql::{
-        internal::GenericEdge, Cursor, Node, PageInfo, RequestContext, Tag,
-        TagConnection,
+        find_inherited_tags, internal::GenericEdge, suggest_tags, Cursor, Node,
+        PageInfo, RequestContext, SmartTags, SuggestedTag, Tag, TagConnection,
+        TagFilter, Timestamp, DEFAULT_SUGGESTED_TAGS,
     },
-    spotify::{Item, PaginatedResponse, SpotifyUri},
+    provider::ItemUri,
+    spotify::{Item, PaginatedResponse},
+};
+use async_graphql::{
+    Context, Enum, FieldResult, InputObject, Object, SimpleObject,
 };
-use async_graphql::{Context, FieldResult, Object, SimpleObject};
 use futures::TryStreamExt;
-use mongodb::bson::doc;
+use itertools::Itertools;
+use mongodb::{bson::doc, Cursor as DbCursor};
+use tokio::sync::OnceCell as AsyncOnceCell;
+
+/// A range of ratings to filter items by. Both ends are inclusive and either
+/// can be omitted. Unrated items never match.
+#[derive(Copy, Clone, Debug, PartialEq, InputObject)]
+pub struct RatingFilter {
+    pub min: Option<u8>,
+    pub max: Option<u8>,
+}
+
+impl RatingFilter {
+    /// Does a rating fall within this range? Unrated items never match.
+    fn matches(&self, rating: Option<u8>) -> bool {
+        rating.map_or(false, |rating| {
+            self.min.map_or(true, |min| rating >= min)
+                && self.max.map_or(true, |max| rating <= max)
+        })
+    }
+}
+
+/// Orders that a lazily loaded [TaggedItemConnection] can be sorted in
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Enum)]
+pub enum ItemSort {
+    /// Highest rated first. Unrated items come last.
+    RatingDesc,
+    /// Lowest rated first. Unrated items come last.
+    RatingAsc,
+}
+
+impl ItemSort {
+    /// Sort a list of item URIs, each paired with its rating, in place
+    fn sort(self, items: &mut [(ItemUri, Option<u8>)]) {
+        match self {
+            Self::RatingDesc => items.sort_by_key(|(_, rating)| {
+                (rating.is_none(), Reverse(*rating))
+            }),
+            Self::RatingAsc => {
+                items.sort_by_key(|(_, rating)| (rating.is_none(), *rating))
+            }
+        }
+    }
+}
 
 /// A Spotify item with its applied tags. The item is always preloaded while the
 /// tags can be fetched eagerly (preloaded) or lazily (loaded from the DB
@@ -22,6 +77,50 @@ pub struct TaggedItemNode {
     /// tags are all preloaded and [TagConnection] won't have to make any
     /// queries for its field resolutions.
     pub tags: Option<Vec<Tag>>,
+    /// The item's document from the DB. This is loaded the first time any
+    /// field that needs it is requested, and shared between the rest of them.
+    /// [TaggedItemConnection] preloads this for all of its nodes in a single
+    /// query.
+    pub document: Arc<AsyncOnceCell<Option<TaggedItemDocument>>>,
+    /// The smart tags that apply to this item. Like `document`, this is
+    /// loaded on first use, and [TaggedItemConnection] preloads it for all of
+    /// its nodes at once.
+    pub smart_tags: Arc<AsyncOnceCell<Vec<Tag>>>,
+}
+
+impl TaggedItemNode {
+    /// Load this item's document from the DB, unless it's been loaded
+    /// already. Returns `None` if the user has never tagged the item.
+    async fn load_document(
+        &self,
+        context: &RequestContext,
+    ) -> ApiResult<Option<&TaggedItemDocument>> {
+        let document = self
+            .document
+            .get_or_try_init(|| {
+                context
+                    .db_handler
+                    .collection_tagged_items()
+                    .find_by_item(&context.user_id, self.item.uri_())
+            })
+            .await?;
+        Ok(document.as_ref())
+    }
+
+    /// Get the tags applied directly to this item, from the preloaded list if
+    /// we have one, otherwise from the DB
+    async fn load_tags(&self, context: &RequestContext) -> ApiResult<Vec<Tag>> {
+        match &self.tags {
+            Some(tags) => Ok(tags.clone()),
+            None => {
+                context
+                    .db_handler
+                    .collection_tagged_items()
+                    .find_tags_by_item(&context.user_id, self.item.uri_())
+                    .await
+            }
+        }
+    }
 }
 
 #[Object]
@@ -41,18 +140,132 @@ impl TaggedItemNode {
         &self.item
     }
 
-    async fn tags(&self) -> FieldResult<TagConnection> {
-        // If we've already loaded the tags for this item, then we can pass them
-        // to the TagConnection and skip a DB query. In some scenarios (e.g.
-        // mutations), we can preload tags for free, but in others we
-        // want to defer the DB query until it's actually necessary.
-        let tag_connection = match &self.tags {
-            Some(tags) => TagConnection::Preloaded { tags },
-            None => TagConnection::ByItem {
-                item_uri: self.item.uri_(),
-            },
+    /// Tags on this item, including any smart tags whose conditions it
+    /// satisfies. If `includeInherited` is set, tags on the item's album
+    /// and/or artists are included too, and marked on their edges.
+    async fn tags(
+        &self,
+        context: &Context<'_>,
+        #[graphql(default)] include_inherited: bool,
+    ) -> FieldResult<TagConnection> {
+        let context = context.data::<RequestContext>()?;
+        let smart_tags = self
+            .smart_tags
+            .get_or_try_init(|| async {
+                SmartTags::load(context)
+                    .await?
+                    .tags_for_item(context, &self.item)
+                    .await
+            })
+            .await?;
+
+        // If there are no virtual tags to merge in, then we can hand off
+        // whatever we have to the TagConnection. If we've already loaded the
+        // tags for this item, we can pass them along and skip a DB query. In
+        // some scenarios (e.g. mutations), we can preload tags for free, but
+        // in others we want to defer the DB query until it's actually
+        // necessary.
+        if smart_tags.is_empty() && !include_inherited {
+            let tag_connection = match &self.tags {
+                Some(tags) => TagConnection::Preloaded {
+                    tags: Cow::Borrowed(tags),
+                    inherited_from: HashMap::new(),
+                },
+                None => TagConnection::ByItem {
+                    item_uri: self.item.uri_(),
+                },
+            };
+            return Ok(tag_connection);
+        }
+
+        // Otherwise we need the real tags now, to merge with the virtual ones
+        let tags = self.load_tags(context).await?;
+        let inherited_from = if include_inherited {
+            find_inherited_tags(context, &self.item, &tags).await?
+        } else {
+            HashMap::new()
         };
-        Ok(tag_connection)
+        // The set dedupes and sorts for us
+        let tags: BTreeSet<Tag> = tags
+            .into_iter()
+            .chain(smart_tags.iter().cloned())
+            .chain(inherited_from.keys().cloned())
+            .collect();
+        Ok(TagConnection::Preloaded {
+            tags: Cow::Owned(tags.into_iter().collect()),
+            inherited_from,
+        })
+    }
+
+    /// When this item was first tagged. Null if the item has never been tagged.
+    async fn created_at(
+        &self,
+        context: &Context<'_>,
+    ) -> FieldResult<Option<Timestamp>> {
+        let context = context.data::<RequestContext>()?;
+        let document = self.load_document(context).await?;
+        Ok(document.and_then(|doc| doc.created_at).map(Timestamp::from))
+    }
+
+    /// When this item's tags last changed. Null if the item has never been
+    /// tagged.
+    async fn updated_at(
+        &self,
+        context: &Context<'_>,
+    ) -> FieldResult<Option<Timestamp>> {
+        let context = context.data::<RequestContext>()?;
+        let document = self.load_document(context).await?;
+        Ok(document.and_then(|doc| doc.updated_at).map(Timestamp::from))
+    }
+
+    /// The user's note on this item. Null if there is no note.
+    async fn note(&self, context: &Context<'_>) -> FieldResult<Option<String>> {
+        let context = context.data::<RequestContext>()?;
+        let document = self.load_document(context).await?;
+        Ok(document.and_then(|doc| doc.note.clone()))
+    }
+
+    /// The user's rating of this item, from 0 to 100. Null if the item hasn't
+    /// been rated.
+    async fn rating(&self, context: &Context<'_>) -> FieldResult<Option<u8>> {
+        let context = context.data::<RequestContext>()?;
+        let document = self.load_document(context).await?;
+        Ok(document.and_then(|doc| doc.rating))
+    }
+
+    /// Tags that might be a good fit for this item, based on how the user has
+    /// tagged other items. Tags that are already on the item aren't included.
+    async fn suggested_tags(
+        &self,
+        context: &Context<'_>,
+        first: Option<usize>,
+    ) -> FieldResult<Vec<SuggestedTag>> {
+        let context = context.data::<RequestContext>()?;
+        let tags = self.load_tags(context).await?;
+        Ok(suggest_tags(
+            context,
+            &self.item,
+            &tags,
+            first.unwrap_or(DEFAULT_SUGGESTED_TAGS),
+        )
+        .await?)
+    }
+
+    /// When a particular tag was applied to this item. Null if the item
+    /// doesn't have the tag.
+    async fn tagged_at(
+        &self,
+        context: &Context<'_>,
+        tag: Tag,
+    ) -> FieldResult<Option<Timestamp>> {
+        let context = context.data::<RequestContext>()?;
+        let document = self.load_document(context).await?;
+        Ok(document.and_then(|doc| {
+            doc.tag_applications
+                .iter()
+                .find(|application| application.tag == tag)
+                .map(|application| application.tagged_at.into())
+        }))
     }
 }
 
@@ -93,16 +306,45 @@ pub enum TaggedItemConnection<'a> {
     ///
     /// This variant currently doesn't support pagination, but that can be
     /// added if necessary.
-    ByUris { uris: &'a [SpotifyUri] },
+    ByUris { uris: &'a [ItemUri] },
 
     /// Lazily load item data, where the items in the collection are defined by
     /// a single tag. When item data is needed, the list of items that match
     /// the tag will be fetched from the DB, _then_ those items will be fetched
-    /// from the Spotify API.
+    /// from the Spotify API. If `include_descendants` is set, items tagged
+    /// with any tag nested under this one will be included too. Items can
+    /// optionally be filtered by rating, and sorted. If the tag is a smart
+    /// tag, `smart_uris` holds the items it applies to, which are included
+    /// even though the tag isn't stored on them. `inherited_uris` holds items
+    /// that inherit the tag from an album or artist, if inheritance was
+    /// requested. Those generally aren't in the DB at all.
     ///
     /// This variant currently doesn't support pagination, but that can be
     /// added if necessary.
-    ByTag { tag: &'a Tag },
+    ByTag {
+        tag: &'a Tag,
+        include_descendants: bool,
+        rating: Option<RatingFilter>,
+        sort: Option<ItemSort>,
+        smart_uris: Vec<ItemUri>,
+        inherited_uris: Vec<ItemUri>,
+        /// Every matching URI, in order. When there are inherited items, these
+        /// can't be counted in the DB, so this is loaded the first time either
+        /// `totalCount` or `edges` needs it, then shared with the other.
+        uris: Arc<AsyncOnceCell<Vec<ItemUri>>>,
+    },
+
+    /// Lazily load item data, where the items in the collection are defined by
+    /// a boolean expression over tags. When item data is needed, the list of
+    /// matching items will be fetched from the DB, _then_ those items will be
+    /// fetched from the Spotify API. Items can optionally be sorted.
+    ///
+    /// This variant currently doesn't support pagination, but that can be
+    /// added if necessary.
+    ByFilter {
+        filter: TagFilter,
+        sort: Option<ItemSort>,
+    },
 }
 
 #[Object]
@@ -117,10 +359,53 @@ impl<'a> TaggedItemConnection<'a> {
             // These URIs aren't paginated, they represent the full data set
             Self::ByUris { uris } => uris.len(),
             // Count the number of matching docs in the DB
-            Self::ByTag { tag } => context
+            Self::ByTag {
+                tag,
+                include_descendants,
+                rating,
+                smart_uris,
+                inherited_uris,
+                ..
+            } if inherited_uris.is_empty() => context
+                .db_handler
+                .collection_tagged_items()
+                .count_by_tag(
+                    &context.user_id,
+                    tag,
+                    *include_descendants,
+                    rating.as_ref(),
+                    smart_uris,
+                )
+                .await?
+                .try_into()?,
+            // Inherited items mostly aren't in the DB, so they have to be
+            // deduped against the tagged items before they can be counted
+            Self::ByTag {
+                tag,
+                include_descendants,
+                rating,
+                sort,
+                smart_uris,
+                inherited_uris,
+                uris,
+            } => uris
+                .get_or_try_init(|| {
+                    find_uris_by_tag(
+                        context,
+                        tag,
+                        *include_descendants,
+                        rating.as_ref(),
+                        *sort,
+                        smart_uris,
+                        inherited_uris,
+                    )
+                })
+                .await?
+                .len(),
+            Self::ByFilter { filter, .. } => context
                 .db_handler
                 .collection_tagged_items()
-                .count_by_tag(&context.user_id, tag)
+                .count_by_filter(&context.user_id, filter)
                 .await?
                 .try_into()?,
         };
@@ -148,7 +433,7 @@ impl<'a> TaggedItemConnection<'a> {
             },
 
             // This variant doesn't support pagination, so offset is always 0
-            Self::ByTag { .. } => {
+            Self::ByTag { .. } | Self::ByFilter { .. } => {
                 // This will hit the DB to count matching records
                 let total_count = self.total_count(context).await?;
                 PageInfo {
@@ -169,6 +454,14 @@ impl<'a> TaggedItemConnection<'a> {
         &self,
         context: &Context<'_>,
     ) -> FieldResult<Vec<TaggedItemEdge>> {
+        // Check these before we shadow the GraphQL context
+        let node_field = context.look_ahead().field("node");
+        let load_documents =
+            ["createdAt", "updatedAt", "note", "rating", "taggedAt"]
+                .iter()
+                .any(|field| node_field.field(field).exists());
+        let load_smart_tags = node_field.field("tags").exists();
+        let load_suggestions = node_field.field("suggestedTags").exists();
         let context = context.data::<RequestContext>()?;
 
         let (items, offset): (Vec<Item>, usize) = match self {
@@ -181,7 +474,7 @@ impl<'a> TaggedItemConnection<'a> {
 
             // We have a list of items, fetch the data from spotify
             Self::ByUris { uris } => {
-                let items = context.spotify.get_items(uris.iter()).await?;
+                let items = load_items(context, uris).await?;
                 // We don't support pagination on this variant yet, so offset
                 // is always 0
                 (items, 0)
@@ -189,30 +482,118 @@ impl<'a> TaggedItemConnection<'a> {
 
             // Fetch all the items for a tag, then fetch data for those items
             // from spotify
-            Self::ByTag { tag } => {
+            Self::ByTag {
+                tag,
+                include_descendants,
+                rating,
+                sort,
+                smart_uris,
+                inherited_uris,
+                uris,
+            } => {
+                // Get URIs from DB, unless totalCount already did
+                let uris = uris
+                    .get_or_try_init(|| {
+                        find_uris_by_tag(
+                            context,
+                            tag,
+                            *include_descendants,
+                            rating.as_ref(),
+                            *sort,
+                            smart_uris,
+                            inherited_uris,
+                        )
+                    })
+                    .await?;
+
+                let items = load_items(context, uris).await?;
+                // We don't support pagination on this variant yet, so offset
+                // is always 0
+                (reorder_items(items, uris, *sort), 0)
+            }
+
+            // Fetch all the items that match the filter, then fetch data for
+            // those items from spotify
+            Self::ByFilter { filter, sort } => {
                 // Get URIs from DB
                 let cursor = context
                     .db_handler
                     .collection_tagged_items()
-                    .find_by_tag(&context.user_id, tag)
+                    .find_by_filter(&context.user_id, filter)
                     .await?;
-                let uris: Vec<SpotifyUri> =
-                    cursor.map_ok(|doc| doc.uri).try_collect().await?;
+                let uris = sorted_uris(cursor, *sort).await?;
 
-                let items = context.spotify.get_items(uris.iter()).await?;
+                let items = load_items(context, &uris).await?;
                 // We don't support pagination on this variant yet, so offset
                 // is always 0
-                (items, 0)
+                (reorder_items(items, &uris, *sort), 0)
             }
         };
 
+        // If any fields from the item documents were requested, grab them for
+        // every item in one query now, rather than doing one query per node
+        let mut documents = if load_documents {
+            let uris: Vec<ItemUri> =
+                items.iter().map(|item| item.uri_().clone()).collect();
+            let documents: HashMap<ItemUri, TaggedItemDocument> = context
+                .db_handler
+                .collection_tagged_items()
+                .find_by_items(&context.user_id, &uris)
+                .await?
+                .map_ok(|doc| (doc.uri.clone(), doc))
+                .try_collect()
+                .await?;
+            Some(documents)
+        } else {
+            None
+        };
+
+        // Same for smart tags, so audio features can be fetched for every
+        // item in one go
+        let mut smart_tags = if load_smart_tags {
+            Some(
+                SmartTags::load(context)
+                    .await?
+                    .tags_for_items(context, &items)
+                    .await?,
+            )
+        } else {
+            None
+        };
+
+        // Suggestions need audio features for each track, so fetch those for
+        // every track at once too
+        if load_suggestions {
+            context
+                .cache
+                .suggestion_stats
+                .load_features(context, &items)
+                .await?;
+        }
+
         // Map items to nodes, then to edges
         let edges = TaggedItemEdge::from_nodes(
             items.into_iter().map(|item| {
+                // Items without a document are marked as loaded too, so they
+                // don't get queried individually
+                let document = match documents.as_mut() {
+                    Some(documents) => Arc::new(AsyncOnceCell::from(
+                        documents.remove(item.uri_()),
+                    )),
+                    None => Default::default(),
+                };
+                let smart_tags = match smart_tags.as_mut() {
+                    Some(smart_tags) => Arc::new(AsyncOnceCell::from(
+                        smart_tags.remove(item.uri_()).unwrap_or_default(),
+                    )),
+                    None => Default::default(),
+                };
                 TaggedItemNode {
                     item,
                     // Tag data isn't present yet, defer loading it
                     tags: None,
+                    document,
+                    smart_tags,
                 }
             }),
             offset,
@@ -222,14 +603,120 @@ impl<'a> TaggedItemConnection<'a> {
     }
 }
 
+/// Fetch data for any number of items, from whichever provider each item
+/// belongs to. Items that don't exist are left out.
+pub async fn load_items(
+    context: &RequestContext,
+    uris: &[ItemUri],
+) -> ApiResult<Vec<Item>> {
+    context
+        .providers
+        .call_grouped(uris, |provider, uris| async move {
+            provider.get_items(&uris).await
+        })
+        .await
+}
+
+/// Collect the URIs from a set of item documents, in the requested order
+async fn sorted_uris(
+    cursor: DbCursor<TaggedItemDocument>,
+    sort: Option<ItemSort>,
+) -> ApiResult<Vec<ItemUri>> {
+    let mut items: Vec<(ItemUri, Option<u8>)> = cursor
+        .map_ok(|doc| (doc.uri, doc.rating))
+        .try_collect()
+        .await?;
+    if let Some(sort) = sort {
+        sort.sort(&mut items);
+    }
+    Ok(items.into_iter().map(|(uri, _)| uri).collect())
+}
+
+/// Get the URIs for a [TaggedItemConnection::ByTag], in the requested order.
+/// Items that have the tag in the DB are combined with items that inherit it.
+async fn find_uris_by_tag(
+    context: &RequestContext,
+    tag: &Tag,
+    include_descendants: bool,
+    rating: Option<&RatingFilter>,
+    sort: Option<ItemSort>,
+    smart_uris: &[ItemUri],
+    inherited_uris: &[ItemUri],
+) -> ApiResult<Vec<ItemUri>> {
+    let collection = context.db_handler.collection_tagged_items();
+    let mut items: Vec<(ItemUri, Option<u8>)> = collection
+        .find_by_tag(
+            &context.user_id,
+            tag,
+            include_descendants,
+            rating,
+            smart_uris,
+        )
+        .await?
+        .map_ok(|doc| (doc.uri, doc.rating))
+        .try_collect()
+        .await?;
+
+    // Most inherited items don't have a document, but look up the ones that
+    // do so we have their ratings
+    let tagged: HashSet<&ItemUri> = items.iter().map(|(uri, _)| uri).collect();
+    let inherited_uris: Vec<ItemUri> = inherited_uris
+        .iter()
+        .filter(|uri| !tagged.contains(uri))
+        .unique()
+        .cloned()
+        .collect();
+    if !inherited_uris.is_empty() {
+        let ratings: HashMap<ItemUri, Option<u8>> = collection
+            .find_by_items(&context.user_id, &inherited_uris)
+            .await?
+            .map_ok(|doc| (doc.uri, doc.rating))
+            .try_collect()
+            .await?;
+        for uri in inherited_uris {
+            let item_rating = ratings.get(&uri).copied().flatten();
+            if rating.map_or(true, |rating| rating.matches(item_rating)) {
+                items.push((uri, item_rating));
+            }
+        }
+    }
+
+    if let Some(sort) = sort {
+        sort.sort(&mut items);
+    }
+    Ok(items.into_iter().map(|(uri, _)| uri).collect())
+}
+
+/// Spotify doesn't return items in the order we ask for them, so if a sort
+/// was requested, put the items back in the order of the given URIs
+fn reorder_items(
+    mut items: Vec<Item>,
+    uris: &[ItemUri],
+    sort: Option<ItemSort>,
+) -> Vec<Item> {
+    if sort.is_some() {
+        let positions: HashMap<&ItemUri, usize> = uris
+            .iter()
+            .enumerate()
+            .map(|(position, uri)| (uri, position))
+            .collect();
+        items.sort_by_key(|item| positions.get(item.uri_()).copied());
+    }
+    items
+}
+
 /// Result of running a search query among taggable items. This is the result of
 /// a single Spotify API request, but Spotify returns the items grouped by type
-/// so that's what we'll do. The 3 connections pagination in lockstep, i.e. they
-/// use the same limit/offset.
+/// so that's what we'll do. The connections paginate in lockstep, i.e. they
+/// use the same limit/offset. Shows and episodes are only present if podcasts
+/// were included in the search.
 /// https://developer.spotify.com/documentation/web-api/reference/#category-search
 #[derive(Clone, Debug, SimpleObject)]
 pub struct ItemSearch<'a> {
     pub tracks: TaggedItemConnection<'a>,
     pub albums: TaggedItemConnection<'a>,
     pub artists: TaggedItemConnection<'a>,
+    pub playlists: TaggedItemConnection<'a>,
+    pub shows: Option<TaggedItemConnection<'a>>,
+    pub episodes: Option<TaggedItemConnection<'a>>,
 }
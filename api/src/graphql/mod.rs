@@ -13,16 +13,24 @@
 //! to prefetch data when we can, but that's a problem for another day.
 
 mod core;
+mod filter;
+mod history;
+mod inheritance;
 mod internal;
 mod item;
 mod mutation;
 mod query;
+mod smart_tags;
+mod suggested_tags;
 mod tag;
 
 pub use crate::graphql::{
-    core::*, internal::*, item::*, mutation::*, query::*, tag::*,
+    core::*, filter::*, history::*, inheritance::*, internal::*, item::*,
+    mutation::*, query::*, smart_tags::*, suggested_tags::*, tag::*,
+};
+use crate::{
+    auth::UserId, db::DbHandler, error::ApiResult, provider::ProviderRegistry,
 };
-use crate::{auth::UserId, db::DbHandler, error::ApiResult, spotify::Spotify};
 use async_graphql::{EmptySubscription, Schema};
 use log::info;
 use std::{fmt::Display, path::Path, sync::Arc};
@@ -38,8 +46,22 @@ use tokio::{fs::File, io::AsyncWriteExt};
 /// caught by static typing.
 pub struct RequestContext {
     pub db_handler: Arc<DbHandler>,
-    pub spotify: Spotify,
+    /// The catalogs that items are loaded from
+    pub providers: ProviderRegistry,
     pub user_id: UserId,
+    /// Data that's shared between resolvers for the rest of the request
+    pub cache: RequestCache,
+}
+
+/// Data that's expensive to load and can be reused by multiple resolvers
+/// within a single request. Everything in here is loaded lazily, the first
+/// time a resolver needs it, and thrown away when the request is done.
+#[derive(Debug, Default)]
+pub struct RequestCache {
+    /// Library-wide statistics and track audio features for [suggest_tags]
+    pub suggestion_stats: SuggestionStats,
+    /// Smart tag definitions, and which items they apply to
+    pub smart_tags: SmartTagCache,
 }
 
 pub type GraphQLSchema = Schema<Query, Mutation, EmptySubscription>;
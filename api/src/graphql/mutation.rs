@@ -1,18 +1,22 @@
 //! All types that are unique to GraphQL mutations
 
 use crate::{
-    error::ApiError,
+    db::{ItemTagChange, TagDocument, TaggedItemDocument},
+    error::{ApiError, ApiResult},
     graphql::{
-        RequestContext, Tag, TagEdge, TagNode, TaggedItemEdge, TaggedItemNode,
+        RequestContext, SmartTag, SmartTagCondition, Tag, TagEdge, TagFilter,
+        TagNode, TaggedItemEdge, TaggedItemNode,
     },
-    spotify::SpotifyUri,
+    provider::ItemUri,
+    spotify::Item,
 };
 use async_graphql::{Context, FieldResult, InputObject, Object, SimpleObject};
-use mongodb::{
-    bson::doc,
-    options::{FindOneAndUpdateOptions, ReturnDocument},
+use futures::TryStreamExt;
+use itertools::Itertools;
+use mongodb::bson::oid::ObjectId;
+use std::{
+    backtrace::Backtrace, collections::HashMap, convert::TryInto, slice,
 };
-use std::backtrace::Backtrace;
 
 /// Root GraphQL mutation
 pub struct Mutation;
@@ -27,48 +31,63 @@ impl Mutation {
         let context = context.data::<RequestContext>()?;
 
         // Look up the item in Spotify first, to get metadata/confirm it's real
-        let item_node = match context.spotify.get_item(&input.item_uri).await? {
+        let (item_node, undo_token) = match context
+            .providers
+            .for_uri(&input.item_uri)?
+            .get_item(&input.item_uri)
+            .await?
+        {
             Some(spotify_item) => {
                 // Do the update query
-                let item_doc = context
+                let old_tags = context
                     .db_handler
                     .collection_tagged_items()
-                    .find_one_and_update(
-                        doc! {"uri": &input.item_uri, "user_id": &context.user_id},
-                        // Add each tag to the doc if it isn't present already
-                        doc! {"$addToSet": {"tags": &input.tag}},
-                        Some(
-                            FindOneAndUpdateOptions::builder()
-                                .upsert(true)
-                                .return_document(ReturnDocument::After)
-                                .build(),
-                        ),
-                    )
-                    .await?
-                    // Handle the None case - this shouldn't be possible because
-                    // we have upsert=true, but just to be safe
-                    .ok_or_else(|| ApiError::Unknown {
-                        message: ("No result from findOneAndUpdate".into()),
-                        backtrace: Backtrace::capture(),
-                    })?;
+                    .add_tag(&context.user_id, &input.item_uri, &input.tag)
+                    .await?;
+                // Start tracking metadata for the tag, if this is its
+                // first use
+                context
+                    .db_handler
+                    .collection_tags()
+                    .ensure_exists(&context.user_id, &input.tag)
+                    .await?;
 
-                Some(TaggedItemNode {
+                let mut tags = old_tags.clone();
+                if !tags.contains(&input.tag) {
+                    tags.push(input.tag.clone());
+                }
+                let change = ItemTagChange::diff(
+                    input.item_uri.clone(),
+                    &old_tags,
+                    &tags,
+                );
+                let undo_token =
+                    Self::save_changes(context, vec![change], Vec::new())
+                        .await?;
+
+                let item_node = TaggedItemNode {
                     item: spotify_item,
-                    // We get tag data preloaded for free from the query
-                    tags: Some(item_doc.tags),
-                })
+                    // We know exactly what the tags are now
+                    tags: Some(tags),
+                    document: Default::default(),
+                    smart_tags: Default::default(),
+                };
+                (Some(item_node), undo_token)
             }
             // URI doesn't exist in spotify
-            None => None,
+            None => (None, None),
         };
         let tag_node = TagNode {
             tag: input.tag,
             item_uris: None,
+            item_count: None,
+            metadata: Default::default(),
         };
 
         Ok(AddTagPayload {
             item_edge: item_node.map(TaggedItemEdge::from),
             tag_edge: tag_node.into(),
+            undo_token,
         })
     }
 
@@ -80,55 +99,820 @@ impl Mutation {
         let context = context.data::<RequestContext>()?;
 
         // Look up the item in Spotify first, to get metadata/confirm it's real
-        let item_node = match context.spotify.get_item(&input.item_uri).await? {
+        let (item_node, undo_token) = match context
+            .providers
+            .get_item(&input.item_uri)
+            .await?
+        {
             Some(spotify_item) => {
-                // Look up tags in mongo - will return None if item doesn't
-                // exist
-                let item_doc_opt = context
-                    .db_handler
-                    .collection_tagged_items()
-                    .find_one_and_update(
-                        doc! {"uri": &input.item_uri, "user_id": &context.user_id},
-                        // Remove the tag from the doc
-                        doc! {"$pull": {"tags": &input.tag}},
-                        Some(
-                            FindOneAndUpdateOptions::builder()
-                                .return_document(ReturnDocument::After)
-                                .build(),
-                        ),
-                    )
-                    .await?;
                 // If the item doesn't exist, just pretend like the tag was
                 // deleted
-                let tags = item_doc_opt
+                let old_tags = context
+                    .db_handler
+                    .collection_tagged_items()
+                    .remove_tag(&context.user_id, &input.item_uri, &input.tag)
+                    .await?
                     .map(|item_doc| item_doc.tags)
                     .unwrap_or_default();
+                let tags: Vec<Tag> = old_tags
+                    .iter()
+                    .filter(|tag| **tag != input.tag)
+                    .cloned()
+                    .collect();
+                let change = ItemTagChange::diff(
+                    input.item_uri.clone(),
+                    &old_tags,
+                    &tags,
+                );
 
-                Some(TaggedItemNode {
+                // If that was the last use of the tag, clean up its
+                // metadata. Grab it first so that an undo can restore it.
+                let tag_metadata = context
+                    .db_handler
+                    .collection_tags()
+                    .find_by_tags(&context.user_id, &change.removed)
+                    .await?;
+                Self::delete_tags_if_unused(context, &change.removed).await?;
+                let undo_token =
+                    Self::save_changes(context, vec![change], tag_metadata)
+                        .await?;
+
+                let item_node = TaggedItemNode {
                     item: spotify_item,
-                    // We get tag data preloaded for free from the query
+                    // We know exactly what the tags are now
                     tags: Some(tags),
-                })
+                    document: Default::default(),
+                    smart_tags: Default::default(),
+                };
+                (Some(item_node), undo_token)
             }
             // URI doesn't exist in spotify
-            None => None,
+            None => (None, None),
         };
         let tag_node = TagNode {
             tag: input.tag,
             item_uris: None,
+            item_count: None,
+            metadata: Default::default(),
         };
 
         Ok(DeleteTagPayload {
             item_edge: item_node.map(TaggedItemEdge::from),
             tag_edge: tag_node.into(),
+            undo_token,
+        })
+    }
+
+    /// Rename a tag on every item it's applied to. Items that already have
+    /// the new tag will just end up with one copy of it.
+    async fn rename_tag(
+        &self,
+        context: &Context<'_>,
+        input: RenameTagInput,
+    ) -> FieldResult<RenameTagPayload> {
+        let context = context.data::<RequestContext>()?;
+        let sources = slice::from_ref(&input.from);
+
+        // Grab the affected items before they change, so we can tell exactly
+        // what changed on each one
+        let item_docs = Self::find_items_with_tags(context, sources).await?;
+        let item_count = context
+            .db_handler
+            .collection_tagged_items()
+            .rename_tag(&context.user_id, &input.from, &input.to)
+            .await?;
+
+        // Metadata for either tag may get moved or dropped, so snapshot both
+        let tags_collection = context.db_handler.collection_tags();
+        let tag_metadata = tags_collection
+            .find_by_tags(
+                &context.user_id,
+                &[input.from.clone(), input.to.clone()],
+            )
+            .await?;
+        tags_collection
+            .rename_tag(&context.user_id, &input.from, &input.to)
+            .await?;
+        // The old tag may not have had any metadata to carry over, so make
+        // sure the new one is tracked either way
+        if item_count > 0 {
+            tags_collection
+                .ensure_exists(&context.user_id, &input.to)
+                .await?;
+        }
+
+        let item_changes = Self::merge_changes(item_docs, sources, &input.to);
+        let undo_token =
+            Self::save_changes(context, item_changes, tag_metadata).await?;
+        let tag_node = TagNode {
+            tag: input.to,
+            item_uris: None,
+            item_count: None,
+            metadata: Default::default(),
+        };
+
+        Ok(RenameTagPayload {
+            tag_edge: tag_node.into(),
+            undo_token,
+        })
+    }
+
+    /// Fold a group of tags into a single target tag, on every item that has
+    /// any of the source tags
+    async fn merge_tags(
+        &self,
+        context: &Context<'_>,
+        input: MergeTagsInput,
+    ) -> FieldResult<MergeTagsPayload> {
+        let context = context.data::<RequestContext>()?;
+
+        // Grab the affected items before they change, so we can tell exactly
+        // what changed on each one
+        let item_docs =
+            Self::find_items_with_tags(context, &input.sources).await?;
+        let item_count = context
+            .db_handler
+            .collection_tagged_items()
+            .merge_tags(&context.user_id, &input.sources, &input.target)
+            .await?;
+
+        // The source tags are gone now, so their metadata goes too. The
+        // target's metadata (if any) is kept as-is.
+        let tags_collection = context.db_handler.collection_tags();
+        let target = &input.target;
+        let sources: Vec<Tag> = input
+            .sources
+            .iter()
+            .filter(|tag| *tag != target)
+            .cloned()
+            .collect();
+        let tag_metadata = tags_collection
+            .find_by_tags(&context.user_id, &sources)
+            .await?;
+        tags_collection
+            .delete_tags(&context.user_id, &sources)
+            .await?;
+        if item_count > 0 {
+            tags_collection
+                .ensure_exists(&context.user_id, &input.target)
+                .await?;
+        }
+
+        let item_changes =
+            Self::merge_changes(item_docs, &input.sources, &input.target);
+        let undo_token =
+            Self::save_changes(context, item_changes, tag_metadata).await?;
+        let tag_node = TagNode {
+            tag: input.target,
+            item_uris: None,
+            item_count: None,
+            metadata: Default::default(),
+        };
+
+        Ok(MergeTagsPayload {
+            tag_edge: tag_node.into(),
+            item_count: item_count.try_into()?,
+            undo_token,
+        })
+    }
+
+    /// Remove a tag from every item it's applied to
+    async fn delete_tag_everywhere(
+        &self,
+        context: &Context<'_>,
+        input: DeleteTagEverywhereInput,
+    ) -> FieldResult<DeleteTagEverywherePayload> {
+        let context = context.data::<RequestContext>()?;
+
+        // Grab the affected items before they change, so we can tell exactly
+        // what changed on each one
+        let item_docs =
+            Self::find_items_with_tags(context, slice::from_ref(&input.tag))
+                .await?;
+        let item_count = context
+            .db_handler
+            .collection_tagged_items()
+            .delete_tag(&context.user_id, &input.tag)
+            .await?;
+        let tags_collection = context.db_handler.collection_tags();
+        let tag_metadata = tags_collection
+            .find_by_tags(&context.user_id, slice::from_ref(&input.tag))
+            .await?;
+        tags_collection
+            .delete_tags(&context.user_id, slice::from_ref(&input.tag))
+            .await?;
+
+        let item_changes = item_docs
+            .into_iter()
+            .map(|doc| {
+                let tags: Vec<Tag> = doc
+                    .tags
+                    .iter()
+                    .filter(|tag| **tag != input.tag)
+                    .cloned()
+                    .collect();
+                ItemTagChange::diff(doc.uri, &doc.tags, &tags)
+            })
+            .collect();
+        let undo_token =
+            Self::save_changes(context, item_changes, tag_metadata).await?;
+        let tag_node = TagNode {
+            tag: input.tag,
+            item_uris: None,
+            item_count: None,
+            metadata: Default::default(),
+        };
+
+        Ok(DeleteTagEverywherePayload {
+            tag_edge: tag_node.into(),
+            item_count: item_count.try_into()?,
+            undo_token,
+        })
+    }
+
+    /// Add and/or remove tags on many items at once. If a tag is in both
+    /// lists, the removal wins. There will be one result per unique item URI,
+    /// including URIs that don't exist in Spotify (those won't be modified).
+    async fn bulk_update_tags(
+        &self,
+        context: &Context<'_>,
+        input: BulkUpdateTagsInput,
+    ) -> FieldResult<BulkUpdateTagsPayload> {
+        let context = context.data::<RequestContext>()?;
+        let item_uris: Vec<ItemUri> =
+            input.item_uris.into_iter().unique().collect();
+        let add = input.add.unwrap_or_default();
+        let remove = input.remove.unwrap_or_default();
+
+        // Look up all the items in Spotify first, to get metadata/confirm
+        // they're real. This is batched so it's only a request or two.
+        let mut items_by_uri: HashMap<ItemUri, Item> = context
+            .providers
+            .get_items(&item_uris)
+            .await?
+            .into_iter()
+            .map(|item| (item.uri_().clone(), item))
+            .collect();
+        let found_uris: Vec<ItemUri> = items_by_uri.keys().cloned().collect();
+
+        // Load the old tags for each item, then do the update, then load the
+        // new tags. Comparing the two tells us exactly what changed.
+        let tagged_items = context.db_handler.collection_tagged_items();
+        let old_tags_by_uri =
+            Self::find_tags_by_uri(context, &found_uris).await?;
+        tagged_items
+            .update_tags_bulk(&context.user_id, &found_uris, &add, &remove)
+            .await?;
+        let mut tags_by_uri =
+            Self::find_tags_by_uri(context, &found_uris).await?;
+        let item_changes: Vec<ItemTagChange> = found_uris
+            .iter()
+            .map(|item_uri| {
+                ItemTagChange::diff(
+                    item_uri.clone(),
+                    old_tags_by_uri
+                        .get(item_uri)
+                        .map(Vec::as_slice)
+                        .unwrap_or_default(),
+                    tags_by_uri
+                        .get(item_uri)
+                        .map(Vec::as_slice)
+                        .unwrap_or_default(),
+                )
+            })
+            .collect();
+
+        // Keep tag metadata in sync
+        if !found_uris.is_empty() {
+            let tags_collection = context.db_handler.collection_tags();
+            for tag in add.iter().filter(|tag| !remove.contains(tag)) {
+                tags_collection.ensure_exists(&context.user_id, tag).await?;
+            }
+        }
+        let tag_metadata = context
+            .db_handler
+            .collection_tags()
+            .find_by_tags(&context.user_id, &remove)
+            .await?;
+        Self::delete_tags_if_unused(context, &remove).await?;
+        let undo_token =
+            Self::save_changes(context, item_changes, tag_metadata).await?;
+
+        // Build one result per input URI, in the order they were given
+        let results = item_uris
+            .into_iter()
+            .map(|item_uri| {
+                let item_edge = items_by_uri.remove(&item_uri).map(|item| {
+                    TaggedItemNode {
+                        item,
+                        // We get tag data preloaded from the query above
+                        tags: Some(
+                            tags_by_uri.remove(&item_uri).unwrap_or_default(),
+                        ),
+                        document: Default::default(),
+                        smart_tags: Default::default(),
+                    }
+                    .into()
+                });
+                BulkUpdateTagsResult {
+                    item_uri,
+                    item_edge,
+                }
+            })
+            .collect();
+
+        Ok(BulkUpdateTagsPayload {
+            results,
+            undo_token,
+        })
+    }
+
+    /// Replace the full set of tags on an item. The payload includes which
+    /// tags were added and removed, relative to the item's previous tags.
+    async fn set_item_tags(
+        &self,
+        context: &Context<'_>,
+        input: SetItemTagsInput,
+    ) -> FieldResult<SetItemTagsPayload> {
+        let context = context.data::<RequestContext>()?;
+        let tags: Vec<Tag> = input.tags.into_iter().unique().collect();
+
+        // Look up the item in Spotify first, to get metadata/confirm it's real
+        let spotify_item = match context
+            .providers
+            .for_uri(&input.item_uri)?
+            .get_item(&input.item_uri)
+            .await?
+        {
+            Some(spotify_item) => spotify_item,
+            // URI doesn't exist in spotify, so there's nothing to change
+            None => {
+                return Ok(SetItemTagsPayload {
+                    item_edge: None,
+                    added_tag_edges: Vec::new(),
+                    removed_tag_edges: Vec::new(),
+                    undo_token: None,
+                })
+            }
+        };
+
+        let old_tags = context
+            .db_handler
+            .collection_tagged_items()
+            .set_tags(&context.user_id, &input.item_uri, &tags)
+            .await?;
+        let change =
+            ItemTagChange::diff(input.item_uri.clone(), &old_tags, &tags);
+
+        // Keep tag metadata in sync
+        let tags_collection = context.db_handler.collection_tags();
+        for tag in &change.added {
+            tags_collection.ensure_exists(&context.user_id, tag).await?;
+        }
+        let tag_metadata = tags_collection
+            .find_by_tags(&context.user_id, &change.removed)
+            .await?;
+        Self::delete_tags_if_unused(context, &change.removed).await?;
+
+        /// Helper to map a list of tags into edges
+        fn to_edges(tags: &[Tag]) -> Vec<TagEdge> {
+            tags.iter()
+                .map(|tag| {
+                    TagNode {
+                        tag: tag.clone(),
+                        item_uris: None,
+                        item_count: None,
+                        metadata: Default::default(),
+                    }
+                    .into()
+                })
+                .collect()
+        }
+        let added_tag_edges = to_edges(&change.added);
+        let removed_tag_edges = to_edges(&change.removed);
+        let undo_token =
+            Self::save_changes(context, vec![change], tag_metadata).await?;
+
+        Ok(SetItemTagsPayload {
+            item_edge: Some(
+                TaggedItemNode {
+                    item: spotify_item,
+                    // We know exactly what the tags are now
+                    tags: Some(tags),
+                    document: Default::default(),
+                    smart_tags: Default::default(),
+                }
+                .into(),
+            ),
+            added_tag_edges,
+            removed_tag_edges,
+            undo_token,
+        })
+    }
+
+    /// Set the metadata for a tag. All metadata fields are replaced, so any
+    /// field that's omitted will be cleared.
+    async fn update_tag_metadata(
+        &self,
+        context: &Context<'_>,
+        input: UpdateTagMetadataInput,
+    ) -> FieldResult<UpdateTagMetadataPayload> {
+        let context = context.data::<RequestContext>()?;
+        let tags_collection = context.db_handler.collection_tags();
+
+        let old_metadata = tags_collection
+            .find_by_tag(&context.user_id, &input.tag)
+            .await?;
+        let new_metadata = tags_collection
+            .update_metadata(
+                &context.user_id,
+                &input.tag,
+                input.description.as_deref(),
+                input.color.as_deref(),
+                input.icon.as_deref(),
+            )
+            .await?;
+        // If the tag didn't have any metadata before, undoing should just
+        // clear out the fields
+        let old_metadata = old_metadata.unwrap_or(TagDocument {
+            description: None,
+            color: None,
+            icon: None,
+            ..new_metadata
+        });
+        let undo_token =
+            Self::save_changes(context, Vec::new(), vec![old_metadata]).await?;
+        let tag_node = TagNode {
+            tag: input.tag,
+            item_uris: None,
+            item_count: None,
+            metadata: Default::default(),
+        };
+
+        Ok(UpdateTagMetadataPayload {
+            tag_edge: tag_node.into(),
+            undo_token,
+        })
+    }
+
+    /// Set the note on an item. An empty or omitted note clears it. Notes
+    /// are kept even if every tag is removed from the item.
+    async fn update_item_note(
+        &self,
+        context: &Context<'_>,
+        input: UpdateItemNoteInput,
+    ) -> FieldResult<UpdateItemNotePayload> {
+        let context = context.data::<RequestContext>()?;
+
+        // Look up the item in Spotify first, to confirm it's real
+        let item_node = match context
+            .providers
+            .for_uri(&input.item_uri)?
+            .get_item(&input.item_uri)
+            .await?
+        {
+            Some(spotify_item) => {
+                let note = input
+                    .note
+                    .as_deref()
+                    .map(str::trim)
+                    .filter(|note| !note.is_empty());
+                context
+                    .db_handler
+                    .collection_tagged_items()
+                    .set_note(&context.user_id, &input.item_uri, note)
+                    .await?;
+                Some(TaggedItemNode {
+                    item: spotify_item,
+                    tags: None,
+                    document: Default::default(),
+                    smart_tags: Default::default(),
+                })
+            }
+            // URI doesn't exist in spotify
+            None => None,
+        };
+
+        Ok(UpdateItemNotePayload {
+            item_edge: item_node.map(TaggedItemEdge::from),
+        })
+    }
+
+    /// Set the user's rating on an item, from 0 to 100. An omitted rating
+    /// clears it. Like notes, ratings are independent of the item's tags.
+    async fn rate_item(
+        &self,
+        context: &Context<'_>,
+        input: RateItemInput,
+    ) -> FieldResult<RateItemPayload> {
+        let context = context.data::<RequestContext>()?;
+
+        // Look up the item in Spotify first, to confirm it's real
+        let item_node = match context
+            .providers
+            .get_item(&input.item_uri)
+            .await?
+        {
+            Some(spotify_item) => {
+                context
+                    .db_handler
+                    .collection_tagged_items()
+                    .set_rating(&context.user_id, &input.item_uri, input.rating)
+                    .await?;
+                Some(TaggedItemNode {
+                    item: spotify_item,
+                    tags: None,
+                    document: Default::default(),
+                    smart_tags: Default::default(),
+                })
+            }
+            // URI doesn't exist in spotify
+            None => None,
+        };
+
+        Ok(RateItemPayload {
+            item_edge: item_node.map(TaggedItemEdge::from),
+        })
+    }
+
+    /// Create a smart tag, or replace the conditions on an existing one. The
+    /// tag will apply to every item that satisfies all of the conditions.
+    async fn set_smart_tag(
+        &self,
+        context: &Context<'_>,
+        input: SetSmartTagInput,
+    ) -> FieldResult<SetSmartTagPayload> {
+        let context = context.data::<RequestContext>()?;
+        let doc = context
+            .db_handler
+            .collection_smart_tags()
+            .save(&context.user_id, &input.tag, input.conditions)
+            .await?;
+        Ok(SetSmartTagPayload {
+            smart_tag: doc.into(),
+        })
+    }
+
+    /// Delete a smart tag. This doesn't affect any items that have the same
+    /// tag applied directly.
+    async fn delete_smart_tag(
+        &self,
+        context: &Context<'_>,
+        input: DeleteSmartTagInput,
+    ) -> FieldResult<DeleteSmartTagPayload> {
+        let context = context.data::<RequestContext>()?;
+        let doc = context
+            .db_handler
+            .collection_smart_tags()
+            .delete(&context.user_id, &input.tag)
+            .await?;
+        Ok(DeleteSmartTagPayload {
+            smart_tag: doc.map(SmartTag::from),
+        })
+    }
+
+    /// Revert a previous tag mutation, using the undo token from its payload.
+    /// This fails without changing anything if the token has expired, or if
+    /// any of the affected items have had those tags changed since.
+    async fn undo(
+        &self,
+        context: &Context<'_>,
+        input: UndoInput,
+    ) -> FieldResult<UndoPayload> {
+        let context = context.data::<RequestContext>()?;
+        let undo_entries = context.db_handler.collection_undo_entries();
+
+        // Tokens are scoped to the user, so another user's token will look
+        // the same as a nonexistent one
+        let id = ObjectId::parse_str(&input.undo_token).map_err(|_| {
+            ApiError::InvalidUndoToken {
+                backtrace: Backtrace::capture(),
+            }
+        })?;
+        // Claim the entry up front, so concurrent requests can't both apply it
+        let entry = undo_entries
+            .claim(&context.user_id, id)
+            .await?
+            .ok_or_else(|| ApiError::InvalidUndoToken {
+                backtrace: Backtrace::capture(),
+            })?;
+
+        // Make sure each item still has exactly the tags that the change
+        // added, and none that it removed. Otherwise we'd clobber a newer
+        // change.
+        let item_uris: Vec<ItemUri> = entry
+            .item_changes
+            .iter()
+            .map(|change| change.item_uri.clone())
+            .collect();
+        let tags_by_uri = Self::find_tags_by_uri(context, &item_uris).await?;
+        let is_applicable = entry.item_changes.iter().all(|change| {
+            change.is_current(
+                tags_by_uri
+                    .get(&change.item_uri)
+                    .map(Vec::as_slice)
+                    .unwrap_or_default(),
+            )
+        });
+        if !is_applicable {
+            undo_entries.unclaim(&entry).await?;
+            return Err(ApiError::UndoConflict {
+                backtrace: Backtrace::capture(),
+            }
+            .into());
+        }
+
+        let reverts: Vec<ItemTagChange> = entry
+            .item_changes
+            .into_iter()
+            .map(ItemTagChange::invert)
+            .collect();
+        context
+            .db_handler
+            .collection_tagged_items()
+            .apply_changes(&context.user_id, &reverts)
+            .await?;
+
+        // Put tag metadata back the way it was before the change
+        let readded_tags: Vec<Tag> = reverts
+            .iter()
+            .flat_map(|change| change.added.iter().cloned())
+            .unique()
+            .collect();
+        let removed_tags: Vec<Tag> = reverts
+            .iter()
+            .flat_map(|change| change.removed.iter().cloned())
+            .unique()
+            .collect();
+        let tags_collection = context.db_handler.collection_tags();
+        Self::delete_tags_if_unused(context, &removed_tags).await?;
+        for tag in &readded_tags {
+            tags_collection.ensure_exists(&context.user_id, tag).await?;
+        }
+        tags_collection.restore(&entry.tag_metadata).await?;
+
+        // The revert is a change in its own right, so it goes in the audit log
+        context
+            .db_handler
+            .collection_tag_events()
+            .record(&context.user_id, &reverts)
+            .await?;
+
+        // Tags on these nodes will be loaded lazily
+        let item_edges = context
+            .providers
+            .get_items(&item_uris)
+            .await?
+            .into_iter()
+            .map(|item| {
+                TaggedItemNode {
+                    item,
+                    tags: None,
+                    document: Default::default(),
+                    smart_tags: Default::default(),
+                }
+                .into()
+            })
+            .collect();
+        let tag_edges = readded_tags
+            .into_iter()
+            .chain(removed_tags)
+            .chain(entry.tag_metadata.into_iter().map(|doc| doc.tag))
+            .unique()
+            .map(|tag| {
+                TagNode {
+                    tag,
+                    item_uris: None,
+                    item_count: None,
+                    metadata: Default::default(),
+                }
+                .into()
+            })
+            .collect();
+
+        Ok(UndoPayload {
+            item_edges,
+            tag_edges,
         })
     }
 }
 
+impl Mutation {
+    /// Get the documents for all items that have any of the given tags
+    async fn find_items_with_tags(
+        context: &RequestContext,
+        tags: &[Tag],
+    ) -> ApiResult<Vec<TaggedItemDocument>> {
+        let filter =
+            TagFilter::Or(tags.iter().cloned().map(TagFilter::Tag).collect());
+        Ok(context
+            .db_handler
+            .collection_tagged_items()
+            .find_by_filter(&context.user_id, &filter)
+            .await?
+            .try_collect()
+            .await?)
+    }
+
+    /// Get the current tags for a group of items, keyed by URI. Items that
+    /// have never been tagged won't be in the map.
+    async fn find_tags_by_uri(
+        context: &RequestContext,
+        item_uris: &[ItemUri],
+    ) -> ApiResult<HashMap<ItemUri, Vec<Tag>>> {
+        Ok(context
+            .db_handler
+            .collection_tagged_items()
+            .find_by_items(&context.user_id, item_uris)
+            .await?
+            .map_ok(|doc| (doc.uri, doc.tags))
+            .try_collect()
+            .await?)
+    }
+
+    /// Figure out what a merge does to each item that has at least one of the
+    /// source tags. This mirrors `TaggedItemsCollection::merge_tags`.
+    fn merge_changes(
+        item_docs: Vec<TaggedItemDocument>,
+        sources: &[Tag],
+        target: &Tag,
+    ) -> Vec<ItemTagChange> {
+        item_docs
+            .into_iter()
+            .map(|doc| {
+                let mut tags: Vec<Tag> = doc
+                    .tags
+                    .iter()
+                    .filter(|tag| *tag == target || !sources.contains(tag))
+                    .cloned()
+                    .collect();
+                if !tags.contains(target) {
+                    tags.push(target.clone());
+                }
+                ItemTagChange::diff(doc.uri, &doc.tags, &tags)
+            })
+            .collect()
+    }
+
+    /// Record a mutation's changes in the audit log, and save them so that the
+    /// mutation can be undone. Returns the undo token, or `None` if the
+    /// mutation didn't actually change anything.
+    async fn save_changes(
+        context: &RequestContext,
+        item_changes: Vec<ItemTagChange>,
+        tag_metadata: Vec<TagDocument>,
+    ) -> ApiResult<Option<String>> {
+        let item_changes: Vec<ItemTagChange> = item_changes
+            .into_iter()
+            .filter(|change| !change.is_empty())
+            .collect();
+        if item_changes.is_empty() && tag_metadata.is_empty() {
+            return Ok(None);
+        }
+
+        context
+            .db_handler
+            .collection_tag_events()
+            .record(&context.user_id, &item_changes)
+            .await?;
+        let id = context
+            .db_handler
+            .collection_undo_entries()
+            .create(&context.user_id, item_changes, tag_metadata)
+            .await?;
+        Ok(Some(id.to_hex()))
+    }
+
+    /// Delete metadata for any of the given tags that are no longer applied
+    /// to any items
+    async fn delete_tags_if_unused(
+        context: &RequestContext,
+        tags: &[Tag],
+    ) -> ApiResult<()> {
+        let tagged_items = context.db_handler.collection_tagged_items();
+        let mut unused_tags = Vec::new();
+        for tag in tags {
+            if tagged_items
+                .count_by_tag(&context.user_id, tag, false, None, &[])
+                .await?
+                == 0
+            {
+                unused_tags.push(tag.clone());
+            }
+        }
+
+        context
+            .db_handler
+            .collection_tags()
+            .delete_tags(&context.user_id, &unused_tags)
+            .await
+    }
+}
+
 /// Input for the `addTag` mutation
 #[derive(Clone, Debug, InputObject)]
 pub struct AddTagInput {
-    pub item_uri: SpotifyUri,
+    pub item_uri: ItemUri,
     pub tag: Tag,
 }
 
@@ -137,12 +921,14 @@ pub struct AddTagInput {
 pub struct AddTagPayload {
     pub item_edge: Option<TaggedItemEdge>,
     pub tag_edge: TagEdge,
+    /// Token for the `undo` mutation. `null` if nothing changed.
+    pub undo_token: Option<String>,
 }
 
 /// Input for the `deleteTag` mutation
 #[derive(Clone, Debug, InputObject)]
 pub struct DeleteTagInput {
-    pub item_uri: SpotifyUri,
+    pub item_uri: ItemUri,
     pub tag: Tag,
 }
 
@@ -151,4 +937,198 @@ pub struct DeleteTagInput {
 pub struct DeleteTagPayload {
     pub item_edge: Option<TaggedItemEdge>,
     pub tag_edge: TagEdge,
+    /// Token for the `undo` mutation. `null` if nothing changed.
+    pub undo_token: Option<String>,
+}
+
+/// Input for the `renameTag` mutation
+#[derive(Clone, Debug, InputObject)]
+pub struct RenameTagInput {
+    pub from: Tag,
+    pub to: Tag,
+}
+
+/// Output for the `renameTag` mutation
+#[derive(Clone, Debug, SimpleObject)]
+pub struct RenameTagPayload {
+    pub tag_edge: TagEdge,
+    /// Token for the `undo` mutation. `null` if nothing changed.
+    pub undo_token: Option<String>,
+}
+
+/// Input for the `mergeTags` mutation
+#[derive(Clone, Debug, InputObject)]
+pub struct MergeTagsInput {
+    pub sources: Vec<Tag>,
+    pub target: Tag,
+}
+
+/// Output for the `mergeTags` mutation
+#[derive(Clone, Debug, SimpleObject)]
+pub struct MergeTagsPayload {
+    pub tag_edge: TagEdge,
+    /// The number of items that had at least one of the source tags
+    pub item_count: usize,
+    /// Token for the `undo` mutation. `null` if nothing changed.
+    pub undo_token: Option<String>,
+}
+
+/// Input for the `deleteTagEverywhere` mutation
+#[derive(Clone, Debug, InputObject)]
+pub struct DeleteTagEverywhereInput {
+    pub tag: Tag,
+}
+
+/// Output for the `deleteTagEverywhere` mutation
+#[derive(Clone, Debug, SimpleObject)]
+pub struct DeleteTagEverywherePayload {
+    pub tag_edge: TagEdge,
+    /// The number of items that the tag was removed from
+    pub item_count: usize,
+    /// Token for the `undo` mutation. `null` if nothing changed.
+    pub undo_token: Option<String>,
+}
+
+/// Input for the `updateTagMetadata` mutation
+#[derive(Clone, Debug, InputObject)]
+pub struct UpdateTagMetadataInput {
+    pub tag: Tag,
+    pub description: Option<String>,
+    /// Any CSS-compatible color
+    pub color: Option<String>,
+    /// Generally an emoji, but can be any short string
+    #[graphql(validator(max_length = 16))]
+    pub icon: Option<String>,
+}
+
+/// Output for the `updateTagMetadata` mutation
+#[derive(Clone, Debug, SimpleObject)]
+pub struct UpdateTagMetadataPayload {
+    pub tag_edge: TagEdge,
+    /// Token for the `undo` mutation. `null` if nothing changed.
+    pub undo_token: Option<String>,
+}
+
+/// Input for the `bulkUpdateTags` mutation
+#[derive(Clone, Debug, InputObject)]
+pub struct BulkUpdateTagsInput {
+    pub item_uris: Vec<ItemUri>,
+    /// Tags to add to every item
+    pub add: Option<Vec<Tag>>,
+    /// Tags to remove from every item
+    pub remove: Option<Vec<Tag>>,
+}
+
+/// Output for the `bulkUpdateTags` mutation
+#[derive(Clone, Debug, SimpleObject)]
+pub struct BulkUpdateTagsPayload {
+    pub results: Vec<BulkUpdateTagsResult>,
+    /// Token for the `undo` mutation. `null` if nothing changed.
+    pub undo_token: Option<String>,
+}
+
+/// The outcome of a bulk tag update for a single item
+#[derive(Clone, Debug, SimpleObject)]
+pub struct BulkUpdateTagsResult {
+    pub item_uri: ItemUri,
+    /// `null` if the item doesn't exist in Spotify, in which case it wasn't
+    /// modified
+    pub item_edge: Option<TaggedItemEdge>,
+}
+
+/// Input for the `setItemTags` mutation
+#[derive(Clone, Debug, InputObject)]
+pub struct SetItemTagsInput {
+    pub item_uri: ItemUri,
+    /// The complete list of tags that the item should have
+    pub tags: Vec<Tag>,
+}
+
+/// Output for the `setItemTags` mutation
+#[derive(Clone, Debug, SimpleObject)]
+pub struct SetItemTagsPayload {
+    pub item_edge: Option<TaggedItemEdge>,
+    /// Tags that the item didn't have before
+    pub added_tag_edges: Vec<TagEdge>,
+    /// Tags that the item had before, but doesn't anymore
+    pub removed_tag_edges: Vec<TagEdge>,
+    /// Token for the `undo` mutation. `null` if nothing changed.
+    pub undo_token: Option<String>,
+}
+
+/// Input for the `updateItemNote` mutation
+#[derive(Clone, Debug, InputObject)]
+pub struct UpdateItemNoteInput {
+    pub item_uri: ItemUri,
+    #[graphql(validator(max_length = 5000))]
+    pub note: Option<String>,
+}
+
+/// Output for the `updateItemNote` mutation
+#[derive(Clone, Debug, SimpleObject)]
+pub struct UpdateItemNotePayload {
+    /// `null` if the item doesn't exist in Spotify, in which case it wasn't
+    /// modified
+    pub item_edge: Option<TaggedItemEdge>,
+}
+
+/// Input for the `rateItem` mutation
+#[derive(Clone, Debug, InputObject)]
+pub struct RateItemInput {
+    pub item_uri: ItemUri,
+    /// From 0 to 100
+    #[graphql(validator(maximum = 100))]
+    pub rating: Option<u8>,
+}
+
+/// Output for the `rateItem` mutation
+#[derive(Clone, Debug, SimpleObject)]
+pub struct RateItemPayload {
+    /// `null` if the item doesn't exist in Spotify, in which case it wasn't
+    /// modified
+    pub item_edge: Option<TaggedItemEdge>,
+}
+
+/// Input for the `setSmartTag` mutation
+#[derive(Clone, Debug, InputObject)]
+pub struct SetSmartTagInput {
+    pub tag: Tag,
+    /// Every condition must be satisfied for the tag to apply
+    #[graphql(validator(min_items = 1))]
+    pub conditions: Vec<SmartTagCondition>,
+}
+
+/// Output for the `setSmartTag` mutation
+#[derive(Clone, Debug, SimpleObject)]
+pub struct SetSmartTagPayload {
+    pub smart_tag: SmartTag,
+}
+
+/// Input for the `deleteSmartTag` mutation
+#[derive(Clone, Debug, InputObject)]
+pub struct DeleteSmartTagInput {
+    pub tag: Tag,
+}
+
+/// Output for the `deleteSmartTag` mutation
+#[derive(Clone, Debug, SimpleObject)]
+pub struct DeleteSmartTagPayload {
+    /// The deleted smart tag, or `null` if there was no smart tag by that name
+    pub smart_tag: Option<SmartTag>,
+}
+
+/// Input for the `undo` mutation
+#[derive(Clone, Debug, InputObject)]
+pub struct UndoInput {
+    /// Token from the payload of the mutation being undone
+    pub undo_token: String,
+}
+
+/// Output for the `undo` mutation
+#[derive(Clone, Debug, SimpleObject)]
+pub struct UndoPayload {
+    /// Items whose tags were reverted
+    pub item_edges: Vec<TaggedItemEdge>,
+    /// Tags that were affected by the revert
+    pub tag_edges: Vec<TagEdge>,
 }
@@ -4,15 +4,28 @@
 use crate::{
     error::{ApiError, ApiResult},
     graphql::{
-        internal::NodeType, Cursor, ItemSearch, Node, RequestContext, Tag,
-        TagConnection, TagNode, TaggedItemConnection, TaggedItemNode,
+        internal::NodeType, Cursor, ItemSearch, ItemSort, Node, RequestContext,
+        SmartTag, SmartTags, Tag, TagConnection, TagCooccurrence,
+        TagEventConnection, TagFilter, TagFilterInput, TagNode, TagSuggestion,
+        TaggedItemConnection, TaggedItemNode,
     },
-    spotify::{Item, PaginatedResponse, PrivateUser, SpotifyUri},
+    provider::ItemUri,
+    spotify::{Item, PaginatedResponse, PrivateUser},
 };
 use async_graphql::{Context, FieldResult, Object};
 use futures::StreamExt;
 use mongodb::bson::doc;
-use std::backtrace::Backtrace;
+use std::{
+    backtrace::Backtrace,
+    convert::{TryFrom, TryInto},
+};
+
+/// Default number of results for [Query::tag_suggestions]
+const DEFAULT_TAG_SUGGESTIONS: usize = 10;
+/// Default page size for [Query::tag_history]
+const DEFAULT_TAG_HISTORY_PAGE: usize = 50;
+/// Largest page size for [Query::tag_history]. Larger requests are clamped.
+const MAX_TAG_HISTORY_PAGE: usize = 500;
 
 /// Root GraphQL query
 pub struct Query;
@@ -38,15 +51,29 @@ impl Query {
             NodeType::TaggedItemNode => {
                 // For items, the value ID is the URI. Look up the item in the
                 // Spotify API
-                let item_uri: SpotifyUri = value_id.parse()?;
-                let item_opt = context.spotify.get_item(&item_uri).await?;
-                item_opt.map(|item| TaggedItemNode { item, tags: None }.into())
+                let item_uri: ItemUri = value_id.parse()?;
+                let item_opt = context
+                    .providers
+                    .for_uri(&item_uri)?
+                    .get_item(&item_uri)
+                    .await?;
+                item_opt.map(|item| {
+                    TaggedItemNode {
+                        item,
+                        tags: None,
+                        document: Default::default(),
+                        smart_tags: Default::default(),
+                    }
+                    .into()
+                })
             }
             NodeType::TagNode => Some(
                 // For tags, the value ID is just the tag
                 TagNode {
                     tag: Tag::new(value_id),
                     item_uris: None,
+                    item_count: None,
+                    metadata: Default::default(),
                 }
                 .into(),
             ),
@@ -60,7 +87,8 @@ impl Query {
     ) -> FieldResult<PrivateUser> {
         Ok(context
             .data::<RequestContext>()?
-            .spotify
+            .providers
+            .primary()?
             .get_current_user()
             .await?)
     }
@@ -68,40 +96,47 @@ impl Query {
     async fn item(
         &self,
         context: &Context<'_>,
-        uri: SpotifyUri,
+        uri: ItemUri,
     ) -> FieldResult<Option<TaggedItemNode>> {
         let context = context.data::<RequestContext>()?;
         // Fetch the item from Spotify
-        let node = context
-            .spotify
-            .get_item(&uri)
-            .await?
-            .map(|item| TaggedItemNode { item, tags: None });
+        let node = context.providers.for_uri(&uri)?.get_item(&uri).await?.map(
+            |item| TaggedItemNode {
+                item,
+                tags: None,
+                document: Default::default(),
+                smart_tags: Default::default(),
+            },
+        );
         Ok(node)
     }
 
     /// Run a search term through spotify. We'll return items grouped by
     /// their type, which is how we get the data from Spotify. This only touches
     /// the Spotify API (not the DB), meaning we defer loading tags down the
-    /// line.
+    /// line. Podcast shows and episodes are only searched if
+    /// `includePodcasts` is set.
     async fn item_search(
         &self,
         context: &Context<'_>,
         #[graphql(validator(min_length = 1))] query: String,
         first: Option<usize>,
         after: Option<Cursor>,
+        #[graphql(default)] include_podcasts: bool,
     ) -> FieldResult<ItemSearch> {
         let context = context.data::<RequestContext>()?;
 
         // Run the search query through spotify. This returns a mapping of
         // results, grouped by item type. i.e. one PaginatedResponse for each
-        // type (track/album/artist)
+        // type (track/album/artist/etc.)
         let mut search_response = context
-            .spotify
+            .providers
+            .primary()?
             .search_items(
                 &query,
                 first,
                 after.map(|cursor| cursor.after_offset()),
+                include_podcasts,
             )
             .await?;
 
@@ -112,7 +147,7 @@ impl Query {
                     .remove(field)
                     .ok_or_else(|| ApiError::Unknown {
                         message: format!(
-                            "Missing field {} in Spotify search response",
+                            "Missing field {} in search response",
                             field
                         ),
                         backtrace: Backtrace::capture(),
@@ -132,26 +167,186 @@ impl Query {
             artists: TaggedItemConnection::Preloaded {
                 paginated_response: load_item_type("artists")?,
             },
+            playlists: TaggedItemConnection::Preloaded {
+                paginated_response: load_item_type("playlists")?,
+            },
+            // These are only present if podcasts were requested
+            shows: search_response.remove("shows").map(|paginated_response| {
+                TaggedItemConnection::Preloaded { paginated_response }
+            }),
+            episodes: search_response.remove("episodes").map(
+                |paginated_response| TaggedItemConnection::Preloaded {
+                    paginated_response,
+                },
+            ),
         };
 
         // Sanity check to make sure we're not getting more data than we need
         debug_assert!(
             search_response.is_empty(),
-            "Spotify search response has extra keys: {:?}",
+            "Search response has extra keys: {:?}",
             search_response.keys()
         );
 
         Ok(rv)
     }
 
-    /// Get all tags. These are loaded lazily by [TagConnection]
-    async fn tags(&self) -> TagConnection {
-        TagConnection::All
+    /// Get all items whose tags match a boolean filter expression. If
+    /// `noteContains` is given, items must also have a note containing that
+    /// text (case-insensitive). Smart tags in the filter match the items they
+    /// apply to. Item data will be loaded lazily, when requested from
+    /// [TaggedItemConnection].
+    async fn tagged_items(
+        &self,
+        context: &Context<'_>,
+        filter: TagFilterInput,
+        note_contains: Option<String>,
+        sort_by: Option<ItemSort>,
+    ) -> FieldResult<TaggedItemConnection> {
+        let context = context.data::<RequestContext>()?;
+        let mut filter = TagFilter::try_from(filter)?;
+        if let Some(note_contains) = note_contains {
+            filter = TagFilter::And(vec![
+                filter,
+                TagFilter::NoteContains(note_contains),
+            ]);
+        }
+        let filter = SmartTags::load(context)
+            .await?
+            .resolve_filter(context, filter)
+            .await?;
+        Ok(TaggedItemConnection::ByFilter {
+            filter,
+            sort: sort_by,
+        })
+    }
+
+    /// Get all items that match a string filter query, e.g.
+    /// `tag:chill -tag:vocal (tag:jazz | tag:soul) type:track`. Smart tags in
+    /// the query match the items they apply to. Item data will be loaded
+    /// lazily, when requested from [TaggedItemConnection].
+    async fn tagged_items_by_query(
+        &self,
+        context: &Context<'_>,
+        #[graphql(validator(min_length = 1))] query: String,
+    ) -> FieldResult<TaggedItemConnection> {
+        let context = context.data::<RequestContext>()?;
+        let filter: TagFilter = query.parse()?;
+        let filter = SmartTags::load(context)
+            .await?
+            .resolve_filter(context, filter)
+            .await?;
+        Ok(TaggedItemConnection::ByFilter { filter, sort: None })
+    }
+
+    /// Get all tags. These are loaded lazily by [TagConnection]. If
+    /// `rootsOnly` is set, only top-level tags are returned, and the rest of
+    /// the hierarchy can be walked via `TagNode.children`. Smart tags aren't
+    /// included, see `smartTags` for those.
+    async fn tags(
+        &self,
+        #[graphql(default)] roots_only: bool,
+    ) -> TagConnection {
+        TagConnection::All { roots_only }
+    }
+
+    /// Get tags that start with a prefix (case-insensitive), for
+    /// autocompletion. The most-used tags come first, with ties broken by
+    /// which tag was used most recently. Smart tags aren't included.
+    async fn tag_suggestions(
+        &self,
+        context: &Context<'_>,
+        prefix: String,
+        #[graphql(validator(minimum = 1))] first: Option<usize>,
+    ) -> FieldResult<Vec<TagSuggestion>> {
+        let context = context.data::<RequestContext>()?;
+        let limit = first.unwrap_or(DEFAULT_TAG_SUGGESTIONS).try_into()?;
+
+        let suggestions = context
+            .db_handler
+            .collection_tagged_items()
+            .find_tag_suggestions(&context.user_id, &prefix, limit)
+            .await?
+            .into_iter()
+            .map(|doc| {
+                Ok(TagSuggestion {
+                    node: TagNode {
+                        tag: doc.tag,
+                        item_uris: None,
+                        item_count: None,
+                        metadata: Default::default(),
+                    },
+                    count: doc.count.try_into()?,
+                })
+            })
+            .collect::<FieldResult<Vec<_>>>()?;
+        Ok(suggestions)
+    }
+
+    /// Get every pair of tags that are applied to at least `minCount` of the
+    /// same items (default 1), with the most common pairs first. Each pair
+    /// also includes its Jaccard similarity, to show how closely the two tags
+    /// overlap regardless of how popular they are.
+    async fn tag_cooccurrence(
+        &self,
+        context: &Context<'_>,
+        min_count: Option<usize>,
+    ) -> FieldResult<Vec<TagCooccurrence>> {
+        let context = context.data::<RequestContext>()?;
+        let min_count = min_count.unwrap_or(1).try_into()?;
+
+        let cooccurrences = context
+            .db_handler
+            .collection_tagged_items()
+            .find_tag_cooccurrences(&context.user_id, min_count)
+            .await?
+            .into_iter()
+            .map(TagCooccurrence::try_from)
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(cooccurrences)
+    }
+
+    /// Get every smart tag the user has defined, sorted by tag
+    async fn smart_tags(
+        &self,
+        context: &Context<'_>,
+    ) -> FieldResult<Vec<SmartTag>> {
+        let context = context.data::<RequestContext>()?;
+        let smart_tags = context
+            .db_handler
+            .collection_smart_tags()
+            .find_all(&context.user_id)
+            .await?
+            .into_iter()
+            .map(SmartTag::from)
+            .collect();
+        Ok(smart_tags)
+    }
+
+    /// Get the history of tag changes, newest first. Filter by item and/or tag
+    /// to see how either one has changed over time. Pages are capped at 500
+    /// events.
+    async fn tag_history(
+        &self,
+        item_uri: Option<ItemUri>,
+        tag: Option<Tag>,
+        #[graphql(validator(minimum = 1))] first: Option<usize>,
+        after: Option<Cursor>,
+    ) -> TagEventConnection {
+        TagEventConnection {
+            item_uri,
+            tag,
+            offset: after.map(|cursor| cursor.after_offset()).unwrap_or(0),
+            limit: first
+                .unwrap_or(DEFAULT_TAG_HISTORY_PAGE)
+                .min(MAX_TAG_HISTORY_PAGE),
+        }
     }
 
     /// Get info for a particular tag. If the tag doesn't exist in the DB, we'll
-    /// pretend like it does and just return a node with no tagged items. Item
-    /// data will be loaded lazily, when requested from [TaggedItemConnection].
+    /// pretend like it does and just return a node with no tagged items. If
+    /// it's a smart tag, every item it applies to is included. Item data will
+    /// be loaded lazily, when requested from [TaggedItemConnection].
     async fn tag(
         &self,
         context: &Context<'_>,
@@ -163,7 +358,7 @@ impl Query {
         let mut cursor = context
             .db_handler
             .collection_tagged_items()
-            .find_by_tag(&context.user_id, &tag)
+            .find_by_tag(&context.user_id, &tag, false, None, &[])
             .await?;
         // Grab the URI for each item
         let mut item_uris = Vec::new();
@@ -171,11 +366,24 @@ impl Query {
             item_uris.push(doc?.uri);
         }
 
+        // Smart tags aren't stored on items, so evaluate them separately
+        for uri in SmartTags::load(context)
+            .await?
+            .find_items(context, &tag)
+            .await?
+        {
+            if !item_uris.contains(&uri) {
+                item_uris.push(uri);
+            }
+        }
+
         // The rest of the item data will be loaded lazily by
         // TaggedItemConnection
         Ok(TagNode {
             tag,
             item_uris: Some(item_uris),
+            item_count: None,
+            metadata: Default::default(),
         })
     }
 }
@@ -0,0 +1,500 @@
+//! Smart tags are virtual tags, defined by a set of conditions over an item's
+//! Spotify data, e.g. "tempo > 150 and energy > 0.8 → running". They're never
+//! stored on items. Instead, they're evaluated on demand against data from
+//! Spotify, whenever an item's tags are loaded or items are queried by tag.
+//!
+//! Spotify has no way to search by these fields, so querying items by a smart
+//! tag only considers items that the user already has a document for (i.e.
+//! items that have been tagged, noted or rated). Even then, evaluating a
+//! smart tag means loading every one of those items from Spotify, so only the
+//! most recently updated [MAX_EVALUATED_ITEMS] are considered.
+
+use crate::{
+    db::SmartTagDocument,
+    error::ApiResult,
+    graphql::{load_items, RequestContext, Tag, TagFilter, TagNode},
+    provider::ItemUri,
+    spotify::{AudioFeatures, Item},
+};
+use async_graphql::{Enum, InputObject, SimpleObject};
+use futures::TryStreamExt;
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, slice};
+use tokio::sync::OnceCell as AsyncOnceCell;
+
+/// Maximum number of items to evaluate when looking for every item that a
+/// smart tag applies to. Each one has to be loaded from Spotify, so this keeps
+/// big libraries from turning a single query into hundreds of requests.
+pub const MAX_EVALUATED_ITEMS: i64 = 1000;
+
+/// A value from an item's Spotify data that a smart tag condition can check.
+/// Items that don't have a field (e.g. audio features on an album) never
+/// satisfy a condition on that field.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Enum, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SmartTagField {
+    /// Audio feature, from 0 to 1. Tracks only.
+    Acousticness,
+    /// Audio feature, from 0 to 1. Tracks only.
+    Danceability,
+    /// Audio feature, from 0 to 1. Tracks only.
+    Energy,
+    /// Audio feature, from 0 to 1. Tracks only.
+    Instrumentalness,
+    /// Audio feature, from 0 to 1. Tracks only.
+    Liveness,
+    /// Audio feature, in decibels (generally between -60 and 0). Tracks only.
+    Loudness,
+    /// Audio feature, from 0 to 1. Tracks only.
+    Speechiness,
+    /// Audio feature, in beats per minute. Tracks only.
+    Tempo,
+    /// Audio feature, from 0 to 1. Tracks only.
+    Valence,
+    /// Audio feature, as a pitch class from 0 (C) to 11 (B). Tracks only.
+    Key,
+    /// Audio feature, 1 for major or 0 for minor. Tracks only.
+    Mode,
+    /// Audio feature, in beats per bar. Tracks only.
+    TimeSignature,
+    /// Tracks and episodes only
+    DurationMs,
+    /// From 0 to 100. Tracks and artists only.
+    Popularity,
+    /// 1 for explicit content, 0 otherwise. Tracks, shows and episodes only.
+    Explicit,
+    /// For tracks, this is the release year of the album. Tracks, albums and
+    /// episodes only.
+    ReleaseYear,
+}
+
+impl SmartTagField {
+    /// Does this field come from the track's audio features? Those require an
+    /// extra Spotify request, so we only fetch them if some condition needs
+    /// them.
+    fn is_audio_feature(self) -> bool {
+        matches!(
+            self,
+            Self::Acousticness
+                | Self::Danceability
+                | Self::Energy
+                | Self::Instrumentalness
+                | Self::Liveness
+                | Self::Loudness
+                | Self::Speechiness
+                | Self::Tempo
+                | Self::Valence
+                | Self::Key
+                | Self::Mode
+                | Self::TimeSignature
+        )
+    }
+
+    /// How far apart two values of this field can be while still counting as
+    /// equal. Audio features are measured, so they're never exactly equal to
+    /// a value the user types in. Every other field is a whole number.
+    fn tolerance(self) -> f64 {
+        match self {
+            Self::Acousticness
+            | Self::Danceability
+            | Self::Energy
+            | Self::Instrumentalness
+            | Self::Liveness
+            | Self::Speechiness
+            | Self::Valence => 0.005,
+            Self::Loudness => 0.05,
+            Self::Tempo => 0.5,
+            Self::Key
+            | Self::Mode
+            | Self::TimeSignature
+            | Self::DurationMs
+            | Self::Popularity
+            | Self::Explicit
+            | Self::ReleaseYear => 0.5,
+        }
+    }
+
+    /// Get the value of this field for an item. Returns `None` if the item
+    /// doesn't have the field.
+    fn value(
+        self,
+        item: &Item,
+        features: Option<&AudioFeatures>,
+    ) -> Option<f64> {
+        match self {
+            Self::Acousticness => features.map(|f| f.acousticness),
+            Self::Danceability => features.map(|f| f.danceability),
+            Self::Energy => features.map(|f| f.energy),
+            Self::Instrumentalness => features.map(|f| f.instrumentalness),
+            Self::Liveness => features.map(|f| f.liveness),
+            Self::Loudness => features.map(|f| f.loudness),
+            Self::Speechiness => features.map(|f| f.speechiness),
+            Self::Tempo => features.map(|f| f.tempo),
+            Self::Valence => features.map(|f| f.valence),
+            Self::Key => features.map(|f| f.key.into()),
+            Self::Mode => features.map(|f| f.mode.into()),
+            Self::TimeSignature => features.map(|f| f.time_signature.into()),
+            Self::DurationMs => match item {
+                Item::Track(track) => Some(track.duration_ms.into()),
+                Item::Episode(episode) => Some(episode.duration_ms.into()),
+                Item::Album(_)
+                | Item::Artist(_)
+                | Item::Playlist(_)
+                | Item::Show(_) => None,
+            },
+            Self::Popularity => match item {
+                Item::Track(track) => Some(track.popularity.into()),
+                Item::Artist(artist) => Some(artist.popularity.into()),
+                Item::Album(_)
+                | Item::Playlist(_)
+                | Item::Show(_)
+                | Item::Episode(_) => None,
+            },
+            Self::Explicit => {
+                let explicit = match item {
+                    Item::Track(track) => track.explicit,
+                    Item::Show(show) => show.explicit,
+                    Item::Episode(episode) => episode.explicit,
+                    Item::Album(_) | Item::Artist(_) | Item::Playlist(_) => {
+                        return None
+                    }
+                };
+                Some(if explicit { 1.0 } else { 0.0 })
+            }
+            Self::ReleaseYear => {
+                let release_date = match item {
+                    Item::Track(track) => &track.album.release_date,
+                    Item::Album(album) => &album.release_date,
+                    Item::Episode(episode) => &episode.release_date,
+                    Item::Artist(_) | Item::Playlist(_) | Item::Show(_) => {
+                        return None
+                    }
+                };
+                // Depending on the precision, this could be YYYY, YYYY-MM or
+                // YYYY-MM-DD, but it always starts with the year
+                release_date.get(..4)?.parse::<u16>().ok().map(f64::from)
+            }
+        }
+    }
+}
+
+/// How a smart tag condition compares a field to its value
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Enum, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SmartTagOperator {
+    Lt,
+    Lte,
+    /// Equal within a tolerance that depends on the field: 0.005 for
+    /// features from 0 to 1, 0.05 for loudness, 0.5 for tempo, and exact for
+    /// fields that are whole numbers
+    Eq,
+    Gte,
+    Gt,
+}
+
+impl SmartTagOperator {
+    fn compare(self, actual: f64, expected: f64, tolerance: f64) -> bool {
+        match self {
+            Self::Lt => actual < expected,
+            Self::Lte => actual <= expected,
+            Self::Eq => (actual - expected).abs() < tolerance,
+            Self::Gte => actual >= expected,
+            Self::Gt => actual > expected,
+        }
+    }
+}
+
+/// A single condition in a smart tag, e.g. `tempo > 150`
+#[derive(Clone, Debug, SimpleObject, InputObject, Serialize, Deserialize)]
+#[graphql(input_name = "SmartTagConditionInput")]
+pub struct SmartTagCondition {
+    pub field: SmartTagField,
+    pub operator: SmartTagOperator,
+    pub value: f64,
+}
+
+impl SmartTagCondition {
+    fn matches(&self, item: &Item, features: Option<&AudioFeatures>) -> bool {
+        self.field.value(item, features).map_or(false, |actual| {
+            self.operator
+                .compare(actual, self.value, self.field.tolerance())
+        })
+    }
+}
+
+/// A virtual tag that applies to every item that satisfies all of its
+/// conditions. Only the 1000 most recently updated items that the user has
+/// tagged, noted or rated are checked when finding a smart tag's items.
+/// Smart tags are never stored on items, so they aren't listed in
+/// `Query.tags`, offered by `Query.tagSuggestions`, or counted by
+/// `TagNode.itemCount` (unless the node came from `Query.tag`).
+#[derive(Clone, Debug, SimpleObject)]
+pub struct SmartTag {
+    pub node: TagNode,
+    pub conditions: Vec<SmartTagCondition>,
+}
+
+impl From<SmartTagDocument> for SmartTag {
+    fn from(doc: SmartTagDocument) -> Self {
+        Self {
+            node: TagNode {
+                tag: doc.tag,
+                item_uris: None,
+                item_count: None,
+                metadata: Default::default(),
+            },
+            conditions: doc.conditions,
+        }
+    }
+}
+
+/// Smart tag data that's shared between every resolver in a request, so that
+/// definitions are only loaded, and the library only evaluated, once. See
+/// [RequestCache](crate::graphql::RequestCache).
+#[derive(Debug, Default)]
+pub struct SmartTagCache {
+    definitions: AsyncOnceCell<SmartTags>,
+    /// Matching URIs for each smart tag, see [SmartTags::matches]
+    matches: AsyncOnceCell<HashMap<Tag, Vec<ItemUri>>>,
+}
+
+/// All of a user's smart tag definitions. These are loaded once, then can be
+/// evaluated against any number of items.
+#[derive(Clone, Debug)]
+pub struct SmartTags(Vec<SmartTagDocument>);
+
+impl SmartTags {
+    /// Load every smart tag defined by the current user. This only hits the
+    /// DB the first time it's called in a request.
+    pub async fn load(context: &RequestContext) -> ApiResult<&Self> {
+        context
+            .cache
+            .smart_tags
+            .definitions
+            .get_or_try_init(|| async {
+                context
+                    .db_handler
+                    .collection_smart_tags()
+                    .find_all(&context.user_id)
+                    .await
+                    .map(Self)
+            })
+            .await
+    }
+
+    /// Is this tag a smart tag?
+    pub fn contains(&self, tag: &Tag) -> bool {
+        self.0.iter().any(|doc| &doc.tag == tag)
+    }
+
+    /// Get every smart tag that applies to a single item, sorted by tag
+    pub async fn tags_for_item(
+        &self,
+        context: &RequestContext,
+        item: &Item,
+    ) -> ApiResult<Vec<Tag>> {
+        let mut tags =
+            self.tags_for_items(context, slice::from_ref(item)).await?;
+        Ok(tags.remove(item.uri_()).unwrap_or_default())
+    }
+
+    /// Get every smart tag that applies to each of a group of items, sorted by
+    /// tag. Audio features are fetched for all the items at once, so use this
+    /// over [Self::tags_for_item] when there are multiple items on hand.
+    /// Items that don't match any smart tags are left out.
+    pub async fn tags_for_items(
+        &self,
+        context: &RequestContext,
+        items: &[Item],
+    ) -> ApiResult<HashMap<ItemUri, Vec<Tag>>> {
+        if self.0.is_empty() {
+            return Ok(HashMap::new());
+        }
+
+        let definitions: Vec<&SmartTagDocument> = self.0.iter().collect();
+        let features = load_features(context, &definitions, items).await?;
+        let mut tags: HashMap<ItemUri, Vec<Tag>> = HashMap::new();
+        for item in items {
+            let matching: Vec<Tag> = definitions
+                .iter()
+                .filter(|doc| evaluate(doc, item, &features))
+                .map(|doc| doc.tag.clone())
+                .collect();
+            if !matching.is_empty() {
+                tags.insert(item.uri_().clone(), matching);
+            }
+        }
+        Ok(tags)
+    }
+
+    /// Find all the items that a smart tag applies to. Returns an empty list
+    /// if the tag isn't a smart tag. See [Self::matches] for the cost of
+    /// this.
+    pub async fn find_items(
+        &self,
+        context: &RequestContext,
+        tag: &Tag,
+    ) -> ApiResult<Vec<ItemUri>> {
+        if !self.contains(tag) {
+            return Ok(Vec::new());
+        }
+        let matches = self.matches(context).await?;
+        Ok(matches.get(tag).cloned().unwrap_or_default())
+    }
+
+    /// Replace every smart tag in a filter with an expression that also
+    /// matches the items that the smart tag applies to. Regular tags are left
+    /// as is, so a tag that's both a smart tag and stored on some items
+    /// matches either way. See [Self::matches] for the cost of this.
+    pub async fn resolve_filter(
+        &self,
+        context: &RequestContext,
+        filter: TagFilter,
+    ) -> ApiResult<TagFilter> {
+        let mut tags = Vec::new();
+        collect_tags(&filter, &mut tags);
+        if !tags.iter().any(|tag| self.contains(tag)) {
+            return Ok(filter);
+        }
+
+        let matches = self.matches(context).await?;
+        Ok(splice_matches(filter, matches))
+    }
+
+    /// Evaluate every smart tag against the user's library, and get the
+    /// matching URIs for each one. This needs item data (and audio features,
+    /// if any condition uses them) from Spotify for every item that's
+    /// evaluated, so it's expensive. To keep that in check, the result is
+    /// cached for the rest of the request, and only the
+    /// [MAX_EVALUATED_ITEMS] most recently updated items are evaluated.
+    async fn matches<'c>(
+        &self,
+        context: &'c RequestContext,
+    ) -> ApiResult<&'c HashMap<Tag, Vec<ItemUri>>> {
+        context
+            .cache
+            .smart_tags
+            .matches
+            .get_or_try_init(|| self.evaluate_library(context))
+            .await
+    }
+
+    /// Evaluate every smart tag against the user's most recently updated
+    /// items. Use [Self::matches] instead, which caches the result.
+    async fn evaluate_library(
+        &self,
+        context: &RequestContext,
+    ) -> ApiResult<HashMap<Tag, Vec<ItemUri>>> {
+        let uris: Vec<ItemUri> = context
+            .db_handler
+            .collection_tagged_items()
+            .find_recent(&context.user_id, MAX_EVALUATED_ITEMS)
+            .await?
+            .map_ok(|doc| doc.uri)
+            .try_collect()
+            .await?;
+        let items = load_items(context, &uris).await?;
+
+        let mut matches: HashMap<Tag, Vec<ItemUri>> = HashMap::new();
+        for (uri, tags) in self.tags_for_items(context, &items).await? {
+            for tag in tags {
+                matches.entry(tag).or_default().push(uri.clone());
+            }
+        }
+        Ok(matches)
+    }
+}
+
+/// Does an item satisfy every condition in a smart tag?
+fn evaluate(
+    doc: &SmartTagDocument,
+    item: &Item,
+    features: &HashMap<ItemUri, AudioFeatures>,
+) -> bool {
+    let features = features.get(item.uri_());
+    doc.conditions
+        .iter()
+        .all(|condition| condition.matches(item, features))
+}
+
+/// Fetch audio features for every track in a group of items, keyed by URI.
+/// If none of the given smart tags have conditions on audio features, this
+/// skips the Spotify request entirely.
+async fn load_features(
+    context: &RequestContext,
+    definitions: &[&SmartTagDocument],
+    items: &[Item],
+) -> ApiResult<HashMap<ItemUri, AudioFeatures>> {
+    let needs_features = definitions.iter().any(|doc| {
+        doc.conditions
+            .iter()
+            .any(|condition| condition.field.is_audio_feature())
+    });
+    if !needs_features {
+        return Ok(HashMap::new());
+    }
+
+    let uris: Vec<&ItemUri> = items
+        .iter()
+        .filter_map(|item| match item {
+            Item::Track(track) => Some(&track.uri),
+            Item::Album(_)
+            | Item::Artist(_)
+            | Item::Playlist(_)
+            | Item::Show(_)
+            | Item::Episode(_) => None,
+        })
+        .collect();
+    Ok(context
+        .providers
+        .get_audio_features_bulk(&uris)
+        .await?
+        .into_iter()
+        .map(|features| (features.uri.clone(), features))
+        .collect())
+}
+
+/// Grab every tag referenced anywhere in a filter
+fn collect_tags(filter: &TagFilter, tags: &mut Vec<Tag>) {
+    match filter {
+        TagFilter::Tag(tag) => tags.push(tag.clone()),
+        TagFilter::And(filters) | TagFilter::Or(filters) => {
+            for filter in filters {
+                collect_tags(filter, tags);
+            }
+        }
+        TagFilter::Not(filter) => collect_tags(filter, tags),
+        TagFilter::ItemType(_)
+        | TagFilter::NoteContains(_)
+        | TagFilter::Rating(_)
+        | TagFilter::Items(_) => {}
+    }
+}
+
+/// Rewrite a filter so that each smart tag also matches its evaluated items
+fn splice_matches(
+    filter: TagFilter,
+    matches: &HashMap<Tag, Vec<ItemUri>>,
+) -> TagFilter {
+    let splice = |filters: Vec<TagFilter>| -> Vec<TagFilter> {
+        filters
+            .into_iter()
+            .map(|filter| splice_matches(filter, matches))
+            .collect()
+    };
+    match filter {
+        TagFilter::Tag(tag) => match matches.get(&tag) {
+            Some(uris) => TagFilter::Or(vec![
+                TagFilter::Tag(tag),
+                TagFilter::Items(uris.clone()),
+            ]),
+            None => TagFilter::Tag(tag),
+        },
+        TagFilter::And(filters) => TagFilter::And(splice(filters)),
+        TagFilter::Or(filters) => TagFilter::Or(splice(filters)),
+        TagFilter::Not(filter) => {
+            TagFilter::Not(Box::new(splice_matches(*filter, matches)))
+        }
+        filter => filter,
+    }
+}
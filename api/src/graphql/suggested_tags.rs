@@ -0,0 +1,572 @@
+//! Tag suggestions for a single item, based on how the user has tagged other
+//! items. Each of the user's existing tags is scored using a few different
+//! signals, and the best ones are suggested:
+//!
+//! - Tags on the item's album or artists
+//! - Tags that are often used alongside the tags already on the item
+//! - For tracks, how similar the track sounds (according to Spotify's audio
+//!   features) to other tracks with each tag
+
+use crate::{
+    error::ApiResult,
+    graphql::{RequestContext, Tag, TagFilter, TagNode},
+    provider::{ItemType, ItemUri},
+    spotify::{AudioFeatures, Item},
+};
+use async_graphql::SimpleObject;
+use futures::TryStreamExt;
+use std::{
+    collections::{HashMap, HashSet},
+    slice,
+    sync::Mutex,
+};
+use tokio::sync::OnceCell as AsyncOnceCell;
+
+/// Default number of results for `TaggedItemNode.suggestedTags`
+pub const DEFAULT_SUGGESTED_TAGS: usize = 5;
+
+/// Weight for a tag that's applied to the item's album
+const ALBUM_WEIGHT: f64 = 1.0;
+/// Weight for a tag that's applied to one of the item's artists
+const ARTIST_WEIGHT: f64 = 0.8;
+/// Weight for a tag that's used alongside one of the item's current tags. This
+/// gets scaled by the Jaccard similarity of the two tags.
+const COOCCURRENCE_WEIGHT: f64 = 1.0;
+/// Weight for a tag whose tracks sound similar to the item. This gets scaled
+/// by the similarity of the audio features.
+const AUDIO_WEIGHT: f64 = 0.6;
+/// Audio similarity (from 0 to 1) below this isn't counted at all, otherwise
+/// every tag would get a little bump
+const MIN_AUDIO_SIMILARITY: f64 = 0.85;
+/// Maximum number of tracks per tag to fetch audio features for. This keeps
+/// the number of Spotify requests down for users with big libraries.
+const AUDIO_SAMPLE_SIZE: usize = 10;
+/// Tempo (in BPM) that maps to 1.0 when normalizing audio features. Every
+/// other feature is already between 0 and 1.
+const MAX_TEMPO: f64 = 250.0;
+
+/// A tag that might be a good fit for an item
+#[derive(Clone, Debug, SimpleObject)]
+pub struct SuggestedTag {
+    pub node: TagNode,
+    /// How strongly the tag is suggested. Scores are only meaningful relative
+    /// to other suggestions for the same item.
+    pub score: f64,
+    /// A short explanation of the strongest reason for the suggestion
+    pub reason: String,
+}
+
+/// Statistics about the user's whole library that feed into suggestions.
+/// These don't depend on the item being scored, so they're computed once per
+/// request (the first time they're needed) and shared between every item that
+/// asks for suggestions. This also caches the audio features of the items
+/// being scored. See [RequestCache](crate::graphql::RequestCache).
+#[derive(Debug, Default)]
+pub struct SuggestionStats {
+    /// For each tag, every tag that's used alongside it, with the Jaccard
+    /// similarity of the pair
+    cooccurrences: AsyncOnceCell<HashMap<Tag, Vec<(Tag, f64)>>>,
+    /// For each tag, the average audio features of a sample of the tracks
+    /// that have it
+    centroids: AsyncOnceCell<HashMap<Tag, [f64; 8]>>,
+    /// Audio features of the tracks being scored, keyed by URI. `None` means
+    /// the provider doesn't have features for that track. Connections fill
+    /// this for every track on the page at once (see [Self::load_features]),
+    /// so that each item doesn't need its own request.
+    features: Mutex<HashMap<ItemUri, Option<[f64; 8]>>>,
+}
+
+impl SuggestionStats {
+    async fn cooccurrences(
+        &self,
+        context: &RequestContext,
+    ) -> ApiResult<&HashMap<Tag, Vec<(Tag, f64)>>> {
+        self.cooccurrences
+            .get_or_try_init(|| load_cooccurrences(context))
+            .await
+    }
+
+    async fn centroids(
+        &self,
+        context: &RequestContext,
+    ) -> ApiResult<&HashMap<Tag, [f64; 8]>> {
+        self.centroids
+            .get_or_try_init(|| load_centroids(context))
+            .await
+    }
+
+    /// Fetch audio features for every track in a list of items that doesn't
+    /// have them cached yet, in one go. Other items are ignored.
+    pub async fn load_features(
+        &self,
+        context: &RequestContext,
+        items: &[Item],
+    ) -> ApiResult<()> {
+        let uris: Vec<&ItemUri> = {
+            let features = self.features.lock().unwrap();
+            items
+                .iter()
+                .filter_map(|item| match item {
+                    Item::Track(track) => Some(&track.uri),
+                    _ => None,
+                })
+                .filter(|uri| !features.contains_key(uri))
+                .collect()
+        };
+        if uris.is_empty() {
+            return Ok(());
+        }
+
+        let fetched: HashMap<ItemUri, [f64; 8]> =
+            get_audio_features(context, uris.iter().copied())
+                .await?
+                .into_iter()
+                .map(|features| {
+                    (features.uri.clone(), feature_vector(&features))
+                })
+                .collect();
+        let mut features = self.features.lock().unwrap();
+        for uri in uris {
+            features.insert(uri.clone(), fetched.get(uri).copied());
+        }
+        Ok(())
+    }
+
+    /// Get the audio features for a single track, fetching them if they
+    /// haven't been loaded already
+    async fn features(
+        &self,
+        context: &RequestContext,
+        item: &Item,
+        uri: &ItemUri,
+    ) -> ApiResult<Option<[f64; 8]>> {
+        self.load_features(context, slice::from_ref(item)).await?;
+        Ok(self.features.lock().unwrap().get(uri).copied().flatten())
+    }
+}
+
+/// Suggest tags for an item, best first. Tags that are already on the item are
+/// never suggested.
+pub async fn suggest_tags(
+    context: &RequestContext,
+    item: &Item,
+    current_tags: &[Tag],
+    limit: usize,
+) -> ApiResult<Vec<SuggestedTag>> {
+    let stats = &context.cache.suggestion_stats;
+    let mut scores = Scores::default();
+    score_related_items(context, item, &mut scores).await?;
+    if !current_tags.is_empty() {
+        score_cooccurrences(
+            stats.cooccurrences(context).await?,
+            current_tags,
+            &mut scores,
+        );
+    }
+    if let Item::Track(track) = item {
+        // Spotify doesn't have features for every track
+        if let Some(features) =
+            stats.features(context, item, &track.uri).await?
+        {
+            score_audio_features(
+                stats.centroids(context).await?,
+                &features,
+                &mut scores,
+            );
+        }
+    }
+    Ok(rank_suggestions(scores, current_tags, limit))
+}
+
+/// Turn scores into suggestions, best first. Tags that are already on the
+/// item are dropped.
+fn rank_suggestions(
+    scores: Scores,
+    current_tags: &[Tag],
+    limit: usize,
+) -> Vec<SuggestedTag> {
+    let mut suggestions: Vec<SuggestedTag> = scores
+        .0
+        .into_iter()
+        .filter(|(tag, _)| !current_tags.contains(tag))
+        .map(|(tag, candidate)| SuggestedTag {
+            node: TagNode {
+                tag,
+                item_uris: None,
+                item_count: None,
+                metadata: Default::default(),
+            },
+            score: candidate.score,
+            reason: candidate.reason,
+        })
+        .collect();
+    // Sort by tag as a tiebreaker, to keep output stable
+    suggestions.sort_by(|a, b| {
+        b.score
+            .total_cmp(&a.score)
+            .then_with(|| a.node.tag.cmp(&b.node.tag))
+    });
+    suggestions.truncate(limit);
+    suggestions
+}
+
+/// Running totals for every tag that's been suggested by at least one signal
+#[derive(Debug, Default)]
+struct Scores(HashMap<Tag, Candidate>);
+
+#[derive(Debug)]
+struct Candidate {
+    /// Sum of every signal for this tag
+    score: f64,
+    /// The biggest single signal for this tag, which provides the reason
+    best: f64,
+    reason: String,
+}
+
+impl Scores {
+    /// Add one signal's score to a tag. The reason is only generated if this
+    /// is the strongest signal so far for the tag.
+    fn add(&mut self, tag: Tag, score: f64, reason: impl FnOnce() -> String) {
+        match self.0.get_mut(&tag) {
+            Some(candidate) => {
+                candidate.score += score;
+                if score > candidate.best {
+                    candidate.best = score;
+                    candidate.reason = reason();
+                }
+            }
+            None => {
+                self.0.insert(
+                    tag,
+                    Candidate {
+                        score,
+                        best: score,
+                        reason: reason(),
+                    },
+                );
+            }
+        }
+    }
+}
+
+/// Score tags that are applied to the item's album and/or artists
+async fn score_related_items(
+    context: &RequestContext,
+    item: &Item,
+    scores: &mut Scores,
+) -> ApiResult<()> {
+    // Map each related item to its weight and a description for the reason
+    let mut related: HashMap<ItemUri, (f64, String)> = HashMap::new();
+    let artists = match item {
+        Item::Track(track) => {
+            related.insert(
+                track.album.uri.clone(),
+                (ALBUM_WEIGHT, format!("album \"{}\"", track.album.name)),
+            );
+            track.artists.as_slice()
+        }
+        Item::Album(album) => album.artists.as_slice(),
+        Item::Artist(_)
+        | Item::Playlist(_)
+        | Item::Show(_)
+        | Item::Episode(_) => &[],
+    };
+    for artist in artists {
+        related.insert(
+            artist.uri.clone(),
+            (ARTIST_WEIGHT, format!("artist \"{}\"", artist.name)),
+        );
+    }
+    if related.is_empty() {
+        return Ok(());
+    }
+
+    let uris: Vec<ItemUri> = related.keys().cloned().collect();
+    let mut cursor = context
+        .db_handler
+        .collection_tagged_items()
+        .find_by_items(&context.user_id, &uris)
+        .await?;
+    while let Some(doc) = cursor.try_next().await? {
+        if let Some((weight, description)) = related.get(&doc.uri) {
+            for tag in doc.tags {
+                scores.add(tag, *weight, || {
+                    format!("Applied to {}", description)
+                });
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Index every co-occurring pair of tags by both of its tags
+async fn load_cooccurrences(
+    context: &RequestContext,
+) -> ApiResult<HashMap<Tag, Vec<(Tag, f64)>>> {
+    let mut cooccurrences: HashMap<Tag, Vec<(Tag, f64)>> = HashMap::new();
+    for doc in context
+        .db_handler
+        .collection_tagged_items()
+        .find_tag_cooccurrences(&context.user_id, 1)
+        .await?
+    {
+        // Each pair only appears once, so store both directions
+        cooccurrences
+            .entry(doc.tag_a.clone())
+            .or_default()
+            .push((doc.tag_b.clone(), doc.jaccard));
+        cooccurrences
+            .entry(doc.tag_b)
+            .or_default()
+            .push((doc.tag_a, doc.jaccard));
+    }
+    Ok(cooccurrences)
+}
+
+/// Average the audio features of a sample of tracks for each tag
+async fn load_centroids(
+    context: &RequestContext,
+) -> ApiResult<HashMap<Tag, [f64; 8]>> {
+    let docs: Vec<_> = context
+        .db_handler
+        .collection_tagged_items()
+        .find_by_filter(&context.user_id, &TagFilter::ItemType(ItemType::Track))
+        .await?
+        .try_collect()
+        .await?;
+
+    // Grab a sample of tracks for each tag. We don't need to leave out the
+    // item being scored, because any tag it's in the sample for is already on
+    // it, so that tag won't be suggested anyway.
+    let mut samples: HashMap<Tag, Vec<ItemUri>> = HashMap::new();
+    for doc in docs {
+        for tag in doc.tags {
+            let sample = samples.entry(tag).or_default();
+            if sample.len() < AUDIO_SAMPLE_SIZE {
+                sample.push(doc.uri.clone());
+            }
+        }
+    }
+    if samples.is_empty() {
+        return Ok(HashMap::new());
+    }
+
+    // Fetch features for every sampled track at once
+    let uris: HashSet<&ItemUri> = samples.values().flatten().collect();
+    let features: HashMap<ItemUri, [f64; 8]> =
+        get_audio_features(context, uris)
+            .await?
+            .into_iter()
+            .map(|features| (features.uri.clone(), feature_vector(&features)))
+            .collect();
+
+    Ok(samples
+        .into_iter()
+        .filter_map(|(tag, uris)| {
+            let vectors: Vec<&[f64; 8]> =
+                uris.iter().filter_map(|uri| features.get(uri)).collect();
+            centroid(&vectors).map(|centroid| (tag, centroid))
+        })
+        .collect())
+}
+
+/// Fetch audio features for any number of tracks, from whichever provider
+/// each track belongs to. Tracks without features are left out.
+async fn get_audio_features<'u>(
+    context: &RequestContext,
+    track_uris: impl IntoIterator<Item = &'u ItemUri>,
+) -> ApiResult<Vec<AudioFeatures>> {
+    context
+        .providers
+        .call_grouped(track_uris, |provider, uris| async move {
+            provider.get_audio_features_bulk(&uris).await
+        })
+        .await
+}
+
+/// Score tags that are often used alongside the item's current tags
+fn score_cooccurrences(
+    cooccurrences: &HashMap<Tag, Vec<(Tag, f64)>>,
+    current_tags: &[Tag],
+    scores: &mut Scores,
+) {
+    for current in current_tags {
+        for (other, jaccard) in cooccurrences
+            .get(current)
+            .map(Vec::as_slice)
+            .unwrap_or_default()
+        {
+            scores.add(other.clone(), COOCCURRENCE_WEIGHT * jaccard, || {
+                format!("Often used with \"{}\"", current)
+            });
+        }
+    }
+}
+
+/// Score tags based on how similar the track sounds to the average of a
+/// sample of other tracks with each tag
+fn score_audio_features(
+    centroids: &HashMap<Tag, [f64; 8]>,
+    target: &[f64; 8],
+    scores: &mut Scores,
+) {
+    for (tag, centroid) in centroids {
+        let similarity = similarity(target, centroid);
+        if similarity >= MIN_AUDIO_SIMILARITY {
+            let reason =
+                || format!("Sounds like other tracks tagged \"{}\"", tag);
+            scores.add(tag.clone(), AUDIO_WEIGHT * similarity, reason);
+        }
+    }
+}
+
+/// Average some feature vectors. Returns `None` if there aren't any.
+fn centroid(vectors: &[&[f64; 8]]) -> Option<[f64; 8]> {
+    if vectors.is_empty() {
+        return None;
+    }
+    let mut centroid = [0.0; 8];
+    for vector in vectors {
+        for (total, value) in centroid.iter_mut().zip(vector.iter()) {
+            *total += value / vectors.len() as f64;
+        }
+    }
+    Some(centroid)
+}
+
+/// Convert audio features into a vector where every dimension is between 0
+/// and 1, so they can be compared evenly
+fn feature_vector(features: &AudioFeatures) -> [f64; 8] {
+    [
+        features.acousticness,
+        features.danceability,
+        features.energy,
+        features.instrumentalness,
+        features.liveness,
+        features.speechiness,
+        features.valence,
+        (features.tempo / MAX_TEMPO).min(1.0),
+    ]
+}
+
+/// Similarity of two normalized feature vectors, from 0 (opposite) to 1
+/// (identical), based on Euclidean distance
+fn similarity(a: &[f64; 8], b: &[f64; 8]) -> f64 {
+    let distance = a
+        .iter()
+        .zip(b.iter())
+        .map(|(a, b)| (a - b).powi(2))
+        .sum::<f64>()
+        .sqrt();
+    // The furthest apart two vectors can be is the diagonal of the unit cube
+    1.0 - distance / (a.len() as f64).sqrt()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tag(tag: &str) -> Tag {
+        Tag::new(tag.into())
+    }
+
+    /// Get the tags and scores of ranked suggestions, for easy comparison
+    fn ranked(
+        scores: Scores,
+        current_tags: &[Tag],
+        limit: usize,
+    ) -> Vec<(String, f64)> {
+        rank_suggestions(scores, current_tags, limit)
+            .into_iter()
+            .map(|suggestion| {
+                (suggestion.node.tag.to_string(), suggestion.score)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_scores_add() {
+        let mut scores = Scores::default();
+        scores.add(tag("a"), 0.5, || "weak".into());
+        scores.add(tag("a"), 1.0, || "strong".into());
+        scores.add(tag("a"), 0.25, || "weakest".into());
+        let candidate = &scores.0[&tag("a")];
+        assert_eq!(candidate.score, 1.75);
+        // The reason comes from the strongest signal
+        assert_eq!(candidate.reason, "strong");
+    }
+
+    #[test]
+    fn test_rank_suggestions() {
+        let mut scores = Scores::default();
+        scores.add(tag("b"), 1.0, String::new);
+        scores.add(tag("a"), 1.0, String::new);
+        scores.add(tag("c"), 2.0, String::new);
+        scores.add(tag("d"), 3.0, String::new);
+        // Best first, ties broken by tag, current tags left out
+        assert_eq!(
+            ranked(scores, &[tag("d")], 10),
+            vec![("c".into(), 2.0), ("a".into(), 1.0), ("b".into(), 1.0)]
+        );
+
+        let mut scores = Scores::default();
+        scores.add(tag("a"), 1.0, String::new);
+        scores.add(tag("b"), 2.0, String::new);
+        assert_eq!(ranked(scores, &[], 1), vec![("b".into(), 2.0)]);
+    }
+
+    #[test]
+    fn test_score_cooccurrences() {
+        let cooccurrences: HashMap<Tag, Vec<(Tag, f64)>> = vec![
+            (tag("a"), vec![(tag("b"), 0.5), (tag("c"), 0.25)]),
+            (tag("b"), vec![(tag("a"), 0.5), (tag("c"), 1.0)]),
+            (tag("c"), vec![(tag("a"), 0.25), (tag("b"), 1.0)]),
+        ]
+        .into_iter()
+        .collect();
+        let mut scores = Scores::default();
+        score_cooccurrences(&cooccurrences, &[tag("a"), tag("b")], &mut scores);
+
+        // Signals from each current tag add up
+        let c = &scores.0[&tag("c")];
+        assert_eq!(c.score, 1.25);
+        assert_eq!(c.reason, "Often used with \"b\"");
+        // Unknown tags don't contribute anything
+        let mut scores = Scores::default();
+        score_cooccurrences(&cooccurrences, &[tag("z")], &mut scores);
+        assert!(scores.0.is_empty());
+    }
+
+    #[test]
+    fn test_score_audio_features() {
+        let centroids: HashMap<Tag, [f64; 8]> = vec![
+            (tag("same"), [0.5; 8]),
+            (tag("close"), [0.55; 8]),
+            (tag("far"), [1.0; 8]),
+        ]
+        .into_iter()
+        .collect();
+        let mut scores = Scores::default();
+        score_audio_features(&centroids, &[0.5; 8], &mut scores);
+
+        assert_eq!(scores.0[&tag("same")].score, AUDIO_WEIGHT);
+        assert!(
+            (scores.0[&tag("close")].score - AUDIO_WEIGHT * 0.95).abs() < 1e-9
+        );
+        // Not similar enough to count
+        assert!(!scores.0.contains_key(&tag("far")));
+    }
+
+    #[test]
+    fn test_centroid() {
+        assert_eq!(centroid(&[]), None);
+        assert_eq!(centroid(&[&[0.0; 8], &[1.0; 8]]), Some([0.5; 8]));
+    }
+
+    #[test]
+    fn test_similarity() {
+        assert_eq!(similarity(&[0.3; 8], &[0.3; 8]), 1.0);
+        // Opposite corners of the unit cube
+        assert_eq!(similarity(&[0.0; 8], &[1.0; 8]), 0.0);
+    }
+}
@@ -1,22 +1,171 @@
 use crate::{
-    error::ParseError,
+    db::{TagCooccurrenceDocument, TagDocument, TagItemCountDocument},
+    error::{ApiResult, ParseError},
     graphql::{
-        core::PageInfo, internal::GenericEdge, item::TaggedItemConnection,
-        Cursor, Node, RequestContext,
+        core::PageInfo, find_inheriting_items, internal::GenericEdge,
+        item::TaggedItemConnection, Cursor, ItemSort, Node, RatingFilter,
+        RequestContext, SmartTags, Timestamp,
     },
-    spotify::SpotifyUri,
+    provider::{ItemType, ItemUri},
 };
-use async_graphql::{scalar, Context, FieldResult, Object};
+use async_graphql::{scalar, Context, FieldResult, Object, SimpleObject};
 use derive_more::Display;
+use itertools::Itertools;
+use log::warn;
 use mongodb::bson::Bson;
+use once_cell::sync::OnceCell;
 use serde::{Deserialize, Serialize};
 use std::{
+    borrow::Cow,
+    collections::{BTreeSet, HashMap},
     convert::{TryFrom, TryInto},
+    num::TryFromIntError,
+    slice,
     str::FromStr,
+    sync::Arc,
 };
+use tokio::sync::OnceCell as AsyncOnceCell;
+use unicode_normalization::UnicodeNormalization;
 
-/// A user-created tag.
-#[derive(Clone, Debug, Display, Serialize, Deserialize)]
+/// The character that separates levels in a hierarchical tag, e.g.
+/// `genre/electronic/house`
+pub const TAG_SEPARATOR: char = '/';
+
+/// The rules that every tag is held to. Set once at startup, from the config.
+static TAG_RULES: OnceCell<TagRules> = OnceCell::new();
+
+/// Configurable rules for normalizing and validating tags, so that variations
+/// of the same tag like `Chill` and ` chill ` don't end up as separate tags.
+/// These are loaded from the `tags` table in the app config.
+///
+/// Regardless of config, every tag is converted to Unicode NFC, and whitespace
+/// is trimmed from each level of the tag and collapsed to a single space.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TagRules {
+    /// Convert tags to lowercase, so that tags that only differ by case are
+    /// treated as the same tag. This is plain Unicode lowercasing, not full
+    /// case folding, so e.g. `ß` and `ss` are still different tags.
+    pub lowercase: bool,
+    /// The maximum length of a tag, in characters, including separators
+    pub max_length: usize,
+    /// Characters that can't appear anywhere in a tag. Control characters are
+    /// always forbidden.
+    pub forbidden_chars: String,
+}
+
+impl Default for TagRules {
+    fn default() -> Self {
+        Self {
+            lowercase: false,
+            max_length: 100,
+            forbidden_chars: String::new(),
+        }
+    }
+}
+
+impl TagRules {
+    /// Set the rules that all tags will be held to. This should be called once
+    /// at startup, before any tags are parsed.
+    pub fn install(self) {
+        if TAG_RULES.set(self).is_err() {
+            warn!("Tag rules were already installed, ignoring new rules");
+        }
+    }
+
+    /// Get the current rules. If none have been installed, the defaults are
+    /// used.
+    pub fn get() -> &'static Self {
+        TAG_RULES.get_or_init(Self::default)
+    }
+
+    /// Convert a tag to its canonical form. This doesn't check if the tag is
+    /// actually valid, see [Self::validate] for that.
+    pub fn normalize(&self, value: &str) -> String {
+        let value = if self.lowercase {
+            value.to_lowercase()
+        } else {
+            value.to_owned()
+        };
+        value
+            .nfc()
+            .collect::<String>()
+            .split(TAG_SEPARATOR)
+            .map(normalize_whitespace)
+            .join(&TAG_SEPARATOR.to_string())
+    }
+
+    /// Check that an already normalized tag follows the rules. Returns an
+    /// error message if not.
+    pub fn validate(&self, value: &str) -> Result<(), String> {
+        if value.is_empty() {
+            Err("Tag cannot be empty".into())
+        } else if value.split(TAG_SEPARATOR).any(str::is_empty) {
+            Err("Tag cannot contain empty levels".into())
+        } else if value.chars().count() > self.max_length {
+            Err(format!(
+                "Tag cannot be longer than {} characters",
+                self.max_length
+            ))
+        } else if let Some(c) = value.chars().find(|c| self.is_forbidden(*c)) {
+            Err(format!("Tag cannot contain {:?}", c))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Force a tag that was stored under older rules into a valid tag.
+    /// Forbidden characters and empty levels are dropped, and anything past
+    /// the maximum length is cut off. Returns `None` if there's nothing left
+    /// of the tag afterwards.
+    pub fn sanitize(&self, value: &str) -> Option<Tag> {
+        let value = self
+            .normalize(value)
+            .split(TAG_SEPARATOR)
+            .map(|level| {
+                // Removing characters can leave behind stray whitespace
+                normalize_whitespace(
+                    &level
+                        .chars()
+                        .filter(|c| !self.is_forbidden(*c))
+                        .collect::<String>(),
+                )
+            })
+            .filter(|level| !level.is_empty())
+            .join(&TAG_SEPARATOR.to_string());
+        let value: String = value.chars().take(self.max_length).collect();
+        value
+            .trim_end_matches(|c: char| c == TAG_SEPARATOR || c.is_whitespace())
+            .parse()
+            .ok()
+    }
+
+    fn is_forbidden(&self, c: char) -> bool {
+        c.is_control() || self.forbidden_chars.contains(c)
+    }
+}
+
+/// Trim whitespace from both ends of a string, and collapse every internal
+/// run of whitespace into a single space
+fn normalize_whitespace(value: &str) -> String {
+    value.split_whitespace().join(" ")
+}
+
+/// A user-created tag. Tags can be nested into a hierarchy by separating each
+/// level with [TAG_SEPARATOR]. Parent tags don't need to exist on their own,
+/// they're implied by their descendants.
+#[derive(
+    Clone,
+    Debug,
+    Display,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Hash,
+    Serialize,
+    Deserialize,
+)]
 #[serde(try_from = "String", into = "String")]
 pub struct Tag(String);
 
@@ -30,20 +179,52 @@ impl Tag {
     pub fn tag(&self) -> &str {
         &self.0
     }
+
+    /// Get the parent of this tag in the hierarchy, i.e. everything before the
+    /// final separator. Returns `None` for top-level tags.
+    pub fn parent(&self) -> Option<Tag> {
+        self.0
+            .rsplit_once(TAG_SEPARATOR)
+            .map(|(parent, _)| Tag::new(parent.into()))
+    }
+
+    /// Get the top-level ancestor of this tag. For a top-level tag, this is
+    /// just the tag itself.
+    pub fn root(&self) -> Tag {
+        match self.0.split_once(TAG_SEPARATOR) {
+            Some((root, _)) => Tag::new(root.into()),
+            None => self.clone(),
+        }
+    }
+
+    /// If this tag is nested anywhere under the given ancestor, get the
+    /// direct child of the ancestor that leads to this tag. E.g. for
+    /// `a/b/c`, the child under `a` is `a/b`. Returns `None` if this tag isn't
+    /// a descendant of the ancestor.
+    pub fn child_under(&self, ancestor: &Tag) -> Option<Tag> {
+        let rest = self
+            .0
+            .strip_prefix(ancestor.tag())?
+            .strip_prefix(TAG_SEPARATOR)?;
+        let child = rest.split(TAG_SEPARATOR).next()?;
+        Some(Tag::new(format!("{}{}{}", ancestor, TAG_SEPARATOR, child)))
+    }
 }
 
 impl FromStr for Tag {
     type Err = ParseError;
 
-    /// Make sure the tag is non-empty
+    /// Normalize the tag, then make sure it follows all the rules. See
+    /// [TagRules] for what those are.
     fn from_str(value: &str) -> Result<Self, Self::Err> {
-        if value.is_empty() {
-            Err(ParseError {
-                message: "Tag cannot be empty".into(),
+        let rules = TagRules::get();
+        let tag = rules.normalize(value);
+        match rules.validate(&tag) {
+            Ok(()) => Ok(Tag::new(tag)),
+            Err(message) => Err(ParseError {
+                message,
                 value: value.into(),
-            })
-        } else {
-            Ok(Tag::new(value.into()))
+            }),
         }
     }
 }
@@ -84,7 +265,35 @@ pub struct TagNode {
     /// loading item **URIs**, not the full item data. So either way, the
     /// full item data won't be preloaded from the Spotify API, we're just
     /// saving a DB query in the eager case.
-    pub item_uris: Option<Vec<SpotifyUri>>,
+    pub item_uris: Option<Vec<ItemUri>>,
+    /// `None` means lazy-load the item counts for this tag. [TagConnection]
+    /// preloads these for all of its nodes in a single query, so that
+    /// rendering a list of tags doesn't require a query per tag.
+    pub item_count: Option<TagItemCount>,
+    /// The tag's metadata. This is loaded the first time any metadata field
+    /// is requested, and shared between the rest of them. [TagConnection]
+    /// preloads this for all of its nodes in a single query.
+    pub metadata: Arc<AsyncOnceCell<Option<TagDocument>>>,
+}
+
+impl TagNode {
+    /// Load this tag's metadata from the DB, unless it's been loaded already.
+    /// Returns `None` if the tag doesn't have any metadata stored.
+    async fn load_metadata(
+        &self,
+        context: &RequestContext,
+    ) -> ApiResult<Option<&TagDocument>> {
+        let metadata = self
+            .metadata
+            .get_or_try_init(|| {
+                context
+                    .db_handler
+                    .collection_tags()
+                    .find_by_tag(&context.user_id, &self.tag)
+            })
+            .await?;
+        Ok(metadata.as_ref())
+    }
 }
 
 #[Object]
@@ -104,22 +313,274 @@ impl TagNode {
         &self.tag
     }
 
-    /// Lazily fetch items for this tag node
-    async fn items(&self) -> TaggedItemConnection {
+    /// User-provided description of the tag
+    async fn description(
+        &self,
+        context: &Context<'_>,
+    ) -> FieldResult<Option<String>> {
+        let context = context.data::<RequestContext>()?;
+        let metadata = self.load_metadata(context).await?;
+        Ok(metadata.and_then(|doc| doc.description.clone()))
+    }
+
+    /// Display color for the tag. Can be any CSS-compatible color.
+    async fn color(
+        &self,
+        context: &Context<'_>,
+    ) -> FieldResult<Option<String>> {
+        let context = context.data::<RequestContext>()?;
+        let metadata = self.load_metadata(context).await?;
+        Ok(metadata.and_then(|doc| doc.color.clone()))
+    }
+
+    /// Display icon for the tag, generally an emoji
+    async fn icon(&self, context: &Context<'_>) -> FieldResult<Option<String>> {
+        let context = context.data::<RequestContext>()?;
+        let metadata = self.load_metadata(context).await?;
+        Ok(metadata.and_then(|doc| doc.icon.clone()))
+    }
+
+    /// When the tag was first applied to an item. This will be null for tags
+    /// that were created before we started tracking creation dates.
+    async fn created_at(
+        &self,
+        context: &Context<'_>,
+    ) -> FieldResult<Option<Timestamp>> {
+        let context = context.data::<RequestContext>()?;
+        let metadata = self.load_metadata(context).await?;
+        Ok(metadata.map(|doc| doc.created_at.into()))
+    }
+
+    /// The number of items that have this tag, broken down by item type. Items
+    /// that only have a descendant of this tag aren't included. Smart tag
+    /// matches are only counted if this node came from `Query.tag`.
+    async fn item_count(
+        &self,
+        context: &Context<'_>,
+    ) -> FieldResult<TagItemCount> {
+        match (&self.item_count, &self.item_uris) {
+            // Counts were preloaded by the parent connection
+            (Some(item_count), _) => Ok(item_count.clone()),
+            // We have the URIs already, and each one contains its item type
+            (None, Some(item_uris)) => Ok(TagItemCount::from_uris(item_uris)),
+            // Nothing preloaded, count just this one tag in the DB
+            (None, None) => {
+                let context = context.data::<RequestContext>()?;
+                let docs = context
+                    .db_handler
+                    .collection_tagged_items()
+                    .count_items_by_tags(
+                        &context.user_id,
+                        slice::from_ref(&self.tag),
+                    )
+                    .await?;
+                let mut item_counts = TagItemCount::from_documents(docs)?;
+                Ok(item_counts.remove(&self.tag).unwrap_or_default())
+            }
+        }
+    }
+
+    /// The parent of this tag in the tag hierarchy, or null for top-level tags
+    async fn parent(&self) -> Option<TagNode> {
+        self.tag.parent().map(|tag| TagNode {
+            tag,
+            item_uris: None,
+            item_count: None,
+            metadata: Default::default(),
+        })
+    }
+
+    /// Lazily fetch the direct children of this tag in the tag hierarchy
+    async fn children(&self) -> TagConnection {
+        TagConnection::Children { tag: &self.tag }
+    }
+
+    /// Lazily fetch items for this tag node. If `includeDescendants` is set,
+    /// items tagged with any tag nested under this one will be included too.
+    /// If `includeInherited` is set, items that inherit the tag from an album
+    /// or artist will be included as well, for up to 50 albums per artist and
+    /// 50 tracks per album. Items can also be filtered by
+    /// rating and sorted. If this is a smart tag, every item it applies to is
+    /// included.
+    async fn items(
+        &self,
+        context: &Context<'_>,
+        #[graphql(default)] include_descendants: bool,
+        #[graphql(default)] include_inherited: bool,
+        rating: Option<RatingFilter>,
+        sort_by: Option<ItemSort>,
+    ) -> FieldResult<TaggedItemConnection> {
         // TODO support pagination on this
-        match &self.item_uris {
-            // We have URIs already, so we can skip the DB query to fetch them
-            Some(item_uris) => TaggedItemConnection::ByUris { uris: item_uris },
+        let connection = match &self.item_uris {
+            // We have URIs already, so we can skip the DB query to fetch them.
+            // These are only for the exact tag though, so we can't use them
+            // for descendants or inheritance, and they don't have ratings
+            // attached
+            Some(item_uris)
+                if !include_descendants
+                    && !include_inherited
+                    && rating.is_none()
+                    && sort_by.is_none() =>
+            {
+                TaggedItemConnection::ByUris { uris: item_uris }
+            }
             // URIs haven't been loaded yet, TaggedItemConnection will have to
-            // do a DB query to get them before doing anything else
-            None => TaggedItemConnection::ByTag { tag: &self.tag },
+            // do a DB query to get them before doing anything else. Smart tag
+            // matches and inherited items can't be found in the DB, so
+            // evaluate those now.
+            _ => {
+                let context = context.data::<RequestContext>()?;
+                let smart_uris = SmartTags::load(context)
+                    .await?
+                    .find_items(context, &self.tag)
+                    .await?;
+                let inherited_uris = if include_inherited {
+                    find_inheriting_items(
+                        context,
+                        &self.tag,
+                        include_descendants,
+                    )
+                    .await?
+                } else {
+                    Vec::new()
+                };
+                TaggedItemConnection::ByTag {
+                    tag: &self.tag,
+                    include_descendants,
+                    rating,
+                    sort: sort_by,
+                    smart_uris,
+                    inherited_uris,
+                    uris: Default::default(),
+                }
+            }
+        };
+        Ok(connection)
+    }
+}
+
+/// The number of items that a tag is applied to, broken down by item type
+#[derive(Clone, Debug, Default, SimpleObject)]
+pub struct TagItemCount {
+    /// The number of items of all types
+    pub total: usize,
+    pub tracks: usize,
+    pub albums: usize,
+    pub artists: usize,
+    pub playlists: usize,
+    pub shows: usize,
+    pub episodes: usize,
+}
+
+impl TagItemCount {
+    /// Count up a list of items by type
+    pub fn from_uris(item_uris: &[ItemUri]) -> Self {
+        let mut item_count = Self::default();
+        for item_uri in item_uris {
+            item_count.add(item_uri.item_type(), 1);
+        }
+        item_count
+    }
+
+    /// Collect the output of a DB count aggregation into counts for each tag
+    pub fn from_documents(
+        docs: Vec<TagItemCountDocument>,
+    ) -> FieldResult<HashMap<Tag, Self>> {
+        let mut item_counts: HashMap<Tag, Self> = HashMap::new();
+        for doc in docs {
+            item_counts
+                .entry(doc.tag)
+                .or_default()
+                .add(doc.item_type, doc.count.try_into()?);
+        }
+        Ok(item_counts)
+    }
+
+    fn add(&mut self, item_type: ItemType, count: usize) {
+        self.total += count;
+        match item_type {
+            ItemType::Track => self.tracks += count,
+            ItemType::Album => self.albums += count,
+            ItemType::Artist => self.artists += count,
+            ItemType::Playlist => self.playlists += count,
+            ItemType::Show => self.shows += count,
+            ItemType::Episode => self.episodes += count,
+            // Users can't be tagged, so these will only show up in the total
+            ItemType::User => {}
+        }
+    }
+}
+
+/// A tag that matched an autocomplete prefix, along with how much it's used
+#[derive(Clone, Debug, SimpleObject)]
+pub struct TagSuggestion {
+    pub node: TagNode,
+    /// The number of items that this tag is applied to
+    pub count: usize,
+}
+
+/// Two tags that are applied to some of the same items, weighted by how much
+/// they overlap. Together these form a graph of which tags go together.
+#[derive(Clone, Debug, SimpleObject)]
+pub struct TagCooccurrence {
+    pub tag_a: TagNode,
+    pub tag_b: TagNode,
+    /// The number of items that have both tags
+    pub count: usize,
+    /// The number of items that have both tags, divided by the number of
+    /// items that have either tag. 1 means the tags are always used together.
+    pub jaccard: f64,
+}
+
+impl TryFrom<TagCooccurrenceDocument> for TagCooccurrence {
+    type Error = TryFromIntError;
+
+    fn try_from(doc: TagCooccurrenceDocument) -> Result<Self, Self::Error> {
+        /// Helper to make a lazy node for a tag
+        fn to_node(tag: Tag) -> TagNode {
+            TagNode {
+                tag,
+                item_uris: None,
+                item_count: None,
+                metadata: Default::default(),
+            }
         }
+
+        Ok(Self {
+            tag_a: to_node(doc.tag_a),
+            tag_b: to_node(doc.tag_b),
+            count: doc.count.try_into()?,
+            jaccard: doc.jaccard,
+        })
     }
 }
 
 // #[derive(Clone, Debug, Deref)]
-// pub struct TagEdge(GenericEdge<TagNode>);
-pub type TagEdge = GenericEdge<TagNode>;
+/// An edge to a tag. When listing an item's tags, the edge also records
+/// where the tag was inherited from, if it isn't applied to the item directly.
+#[derive(Clone, Debug)]
+pub struct TagEdge {
+    pub node: TagNode,
+    pub cursor: Cursor,
+    pub inherited_from: Vec<ItemUri>,
+}
+
+impl From<GenericEdge<TagNode>> for TagEdge {
+    fn from(edge: GenericEdge<TagNode>) -> Self {
+        Self {
+            node: edge.node,
+            cursor: edge.cursor,
+            inherited_from: Vec::new(),
+        }
+    }
+}
+
+// Mutations usually return edges, so this makes it easy to map nodes directly
+impl From<TagNode> for TagEdge {
+    fn from(node: TagNode) -> Self {
+        GenericEdge::from(node).into()
+    }
+}
 
 #[Object]
 impl TagEdge {
@@ -130,6 +591,13 @@ impl TagEdge {
     async fn cursor(&self) -> &Cursor {
         &self.cursor
     }
+
+    /// The albums and/or artists that the item inherited this tag from. Empty
+    /// if the tag is applied to the item directly, or if this edge isn't part
+    /// of an item's tags.
+    async fn inherited_from(&self) -> &[ItemUri] {
+        &self.inherited_from
+    }
 }
 
 /// "Connection" is a concept from Relay. Read more: https://graphql.org/learn/pagination/
@@ -145,21 +613,90 @@ pub enum TagConnection<'a> {
     /// This variant should be used whenever tag data is already present, but
     /// you shouldn't prefetch data just for the purposes of using this
     /// variant. In those cases, use one of the lazily loaded variants instead.
-    Preloaded { tags: &'a [Tag] },
+    /// Tags are usually borrowed, but can be owned if they had to be merged
+    /// with virtual tags (e.g. smart tags) first. Any tags that are inherited
+    /// from another item are mapped to the item(s) they came from in
+    /// `inherited_from`.
+    Preloaded {
+        tags: Cow<'a, [Tag]>,
+        inherited_from: HashMap<Tag, Vec<ItemUri>>,
+    },
 
     /// Lazily load tag data for **all** tags defined by this user. The list of
     /// tags that this user has created will be fetched lazily, as needed.
     ///
+    /// If `roots_only` is set, only top-level tags are included. Combined with
+    /// [TagNode]'s `children` field, this gives a tree view of the tag
+    /// hierarchy. Top-level tags that only exist implicitly (because some tag
+    /// is nested under them) are included.
+    ///
     /// This variant currently doesn't support pagination, but that can be
     /// added if necessary.
-    All,
+    All { roots_only: bool },
 
     /// Lazily load tag data, where the list of tags is defined by an item URI.
     /// Any tag that is applied to the item will be included.
     ///
     /// This variant currently doesn't support pagination, but that can be
     /// added if necessary.
-    ByItem { item_uri: &'a SpotifyUri },
+    ByItem { item_uri: &'a ItemUri },
+
+    /// Lazily load the direct children of a tag in the tag hierarchy. Like
+    /// with top-level tags, children that only exist implicitly are included.
+    ///
+    /// This variant currently doesn't support pagination, but that can be
+    /// added if necessary.
+    Children { tag: &'a Tag },
+}
+
+impl<'a> TagConnection<'a> {
+    /// Get the full list of tags in this connection, whether it's preloaded
+    /// or we have to go to the DB
+    async fn load_tags(&self, context: &RequestContext) -> ApiResult<Vec<Tag>> {
+        let collection = context.db_handler.collection_tagged_items();
+
+        let tags = match self {
+            // Tags have been loaded eagerly, so no I/O required here
+            Self::Preloaded { tags, .. } => tags.to_vec(),
+
+            // Tags haven't been loaded yet, fetch all of them
+            Self::All { roots_only: false } => {
+                collection.find_tags(&context.user_id).await?
+            }
+
+            // Tags haven't been loaded yet, fetch all of them then collapse
+            // them down to their roots. The set dedupes and sorts for us.
+            Self::All { roots_only: true } => collection
+                .find_tags(&context.user_id)
+                .await?
+                .iter()
+                .map(Tag::root)
+                .collect::<BTreeSet<_>>()
+                .into_iter()
+                .collect(),
+
+            // Tags haven't been loaded yet, so fetch them now, filtered by a
+            // single item
+            Self::ByItem { item_uri } => {
+                collection
+                    .find_tags_by_item(&context.user_id, item_uri)
+                    .await?
+            }
+
+            // Fetch all tags, then grab the child of the parent that leads to
+            // each descendant
+            Self::Children { tag } => collection
+                .find_tags(&context.user_id)
+                .await?
+                .iter()
+                .filter_map(|descendant| descendant.child_under(tag))
+                .collect::<BTreeSet<_>>()
+                .into_iter()
+                .collect(),
+        };
+
+        Ok(tags)
+    }
 }
 
 #[Object]
@@ -169,9 +706,9 @@ impl<'a> TagConnection<'a> {
         let collection = context.db_handler.collection_tagged_items();
 
         let total_count = match self {
-            Self::Preloaded { tags } => tags.len(),
+            Self::Preloaded { tags, .. } => tags.len(),
             // Count all tags in the DB for this user
-            Self::All => {
+            Self::All { roots_only: false } => {
                 collection.count_tags(&context.user_id).await?.try_into()?
             }
             // Count all tags in the DB for a single user+item
@@ -179,6 +716,11 @@ impl<'a> TagConnection<'a> {
                 .count_tags_by_item(&context.user_id, item_uri)
                 .await?
                 .try_into()?,
+            // The hierarchy has to be built from the full list of tags, so
+            // there's no shortcut for counting here
+            Self::All { roots_only: true } | Self::Children { .. } => {
+                self.load_tags(context).await?.len()
+            }
         };
 
         Ok(total_count)
@@ -188,7 +730,7 @@ impl<'a> TagConnection<'a> {
         // We don't actually support paginating through tags in any way yet,
         // so the offset is always 0 on these
         let page_info = match self {
-            Self::Preloaded { tags } => PageInfo {
+            Self::Preloaded { tags, .. } => PageInfo {
                 offset: 0,
                 page_len: tags.len(),
                 has_previous_page: false,
@@ -196,7 +738,7 @@ impl<'a> TagConnection<'a> {
             },
 
             // This variant doesn't support pagination, so offset is always 0
-            Self::All { .. } | Self::ByItem { .. } => {
+            Self::All { .. } | Self::ByItem { .. } | Self::Children { .. } => {
                 // In either case, this will hit the DB to count matches
                 let total_count = self.total_count(context).await?;
                 PageInfo {
@@ -212,36 +754,177 @@ impl<'a> TagConnection<'a> {
     }
 
     async fn edges(&self, context: &Context<'_>) -> FieldResult<Vec<TagEdge>> {
+        // Check these before we shadow the GraphQL context
+        let node_field = context.look_ahead().field("node");
+        let load_item_counts = node_field.field("itemCount").exists();
+        let load_metadata = ["description", "color", "icon", "createdAt"]
+            .iter()
+            .any(|field| node_field.field(field).exists());
         let context = context.data::<RequestContext>()?;
-        let collection = context.db_handler.collection_tagged_items();
+        let tags = self.load_tags(context).await?;
 
-        // Get a list of raw tags, whether it pre-loaded or we have to go
-        // to the DB
-        let tags: Vec<Tag> = match self {
-            // Tags have been loaded eagerly, so no I/O required here
-            Self::Preloaded { tags } => tags.to_vec(),
-
-            // Tags haven't been loaded yet, fetch all of them
-            Self::All => collection.find_tags(&context.user_id).await?,
+        // If item counts were requested, grab them for every tag in one query
+        // now, rather than doing one query per node
+        let mut item_counts = if load_item_counts {
+            let docs = context
+                .db_handler
+                .collection_tagged_items()
+                .count_items_by_tags(&context.user_id, &tags)
+                .await?;
+            Some(TagItemCount::from_documents(docs)?)
+        } else {
+            None
+        };
 
-            // Tags haven't been loaded yet, so fetch them now, filtered by a
-            // single item
-            Self::ByItem { item_uri } => {
-                collection
-                    .find_tags_by_item(&context.user_id, item_uri)
-                    .await?
-            }
+        // Same deal for metadata
+        let mut metadata_docs = if load_metadata {
+            let docs = context
+                .db_handler
+                .collection_tags()
+                .find_by_tags(&context.user_id, &tags)
+                .await?;
+            Some(
+                docs.into_iter()
+                    .map(|doc| (doc.tag.clone(), doc))
+                    .collect::<HashMap<_, _>>(),
+            )
+        } else {
+            None
         };
 
         // Map individual tags into graphql edges
         let edges = GenericEdge::from_nodes(
-            tags.into_iter().map(|tag| TagNode {
-                tag,
-                // Defer loading the items for this tag until needed
-                item_uris: None,
+            tags.into_iter().map(|tag| {
+                // Tags that aren't on any items (e.g. implicit parents) are
+                // missing from the aggregation, so they get a count of 0
+                let item_count = item_counts.as_mut().map(|item_counts| {
+                    item_counts.remove(&tag).unwrap_or_default()
+                });
+                // Tags without a metadata document are marked as loaded too,
+                // so they don't get queried individually
+                let metadata = match metadata_docs.as_mut() {
+                    Some(docs) => {
+                        Arc::new(AsyncOnceCell::from(docs.remove(&tag)))
+                    }
+                    None => Default::default(),
+                };
+                TagNode {
+                    tag,
+                    // Defer loading the items for this tag until needed
+                    item_uris: None,
+                    item_count,
+                    metadata,
+                }
             }),
             0,
-        );
+        )
+        .into_iter()
+        .map(|edge| {
+            let mut edge = TagEdge::from(edge);
+            if let Self::Preloaded { inherited_from, .. } = self {
+                if let Some(sources) = inherited_from.get(&edge.node.tag) {
+                    edge.inherited_from = sources.clone();
+                }
+            }
+            edge
+        })
+        .collect();
         Ok(edges)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rules() -> TagRules {
+        TagRules {
+            lowercase: false,
+            max_length: 10,
+            forbidden_chars: "#".into(),
+        }
+    }
+
+    fn sanitize(rules: &TagRules, value: &str) -> Option<String> {
+        rules.sanitize(value).map(|tag| tag.to_string())
+    }
+
+    #[test]
+    fn test_normalize_whitespace() {
+        assert_eq!(normalize_whitespace("  lo   fi \t"), "lo fi");
+        assert_eq!(normalize_whitespace("lo\n\u{a0}fi"), "lo fi");
+        assert_eq!(normalize_whitespace("chill"), "chill");
+        assert_eq!(normalize_whitespace("   "), "");
+    }
+
+    #[test]
+    fn test_normalize() {
+        let rules = rules();
+        // Whitespace is trimmed and collapsed within each level
+        assert_eq!(rules.normalize("  genre /  lo   fi / "), "genre/lo fi/");
+        // Decomposed characters are composed
+        assert_eq!(rules.normalize("cafe\u{301}"), "caf\u{e9}");
+        assert_eq!(rules.normalize("Chill"), "Chill");
+        // Normalizing doesn't enforce the other rules
+        assert_eq!(rules.normalize("#chill"), "#chill");
+    }
+
+    #[test]
+    fn test_normalize_lowercase() {
+        let rules = TagRules {
+            lowercase: true,
+            ..rules()
+        };
+        assert_eq!(rules.normalize("Genre/ChILL"), "genre/chill");
+        assert_eq!(rules.normalize("\u{c9}T\u{c9}"), "\u{e9}t\u{e9}");
+        // Lowercasing happens before composing, so the result is still NFC
+        assert_eq!(rules.normalize("E\u{301}"), "\u{e9}");
+    }
+
+    #[test]
+    fn test_validate() {
+        let rules = rules();
+        assert_eq!(rules.validate("genre/chill"), Ok(()));
+        assert_eq!(rules.validate(""), Err("Tag cannot be empty".into()));
+        assert_eq!(
+            rules.validate("genre//chill"),
+            Err("Tag cannot contain empty levels".into())
+        );
+        // Length is counted in characters, not bytes
+        assert_eq!(rules.validate(&"\u{e9}".repeat(10)), Ok(()));
+        assert_eq!(
+            rules.validate(&"\u{e9}".repeat(11)),
+            Err("Tag cannot be longer than 10 characters".into())
+        );
+        assert_eq!(
+            rules.validate("#chill"),
+            Err("Tag cannot contain '#'".into())
+        );
+        assert_eq!(
+            rules.validate("chill\u{7}"),
+            Err("Tag cannot contain '\\u{7}'".into())
+        );
+    }
+
+    #[test]
+    fn test_sanitize() {
+        let rules = rules();
+        assert_eq!(sanitize(&rules, " Chill "), Some("Chill".into()));
+        // Forbidden characters are dropped, along with any whitespace or
+        // levels that leaves behind
+        assert_eq!(sanitize(&rules, "lo # fi"), Some("lo fi".into()));
+        assert_eq!(sanitize(&rules, "#/chill//"), Some("chill".into()));
+        // Long tags are cut off on a character boundary, and don't end in a
+        // separator or whitespace
+        assert_eq!(
+            sanitize(&rules, &"\u{e9}".repeat(12)),
+            Some("\u{e9}".repeat(10))
+        );
+        assert_eq!(sanitize(&rules, "genre/jazzy"), Some("genre/jazz".into()));
+        assert_eq!(sanitize(&rules, "abcdefghi/j"), Some("abcdefghi".into()));
+        assert_eq!(sanitize(&rules, "abcdefghi jk"), Some("abcdefghi".into()));
+        // Nothing left
+        assert_eq!(sanitize(&rules, ""), None);
+        assert_eq!(sanitize(&rules, " # / ## "), None);
+    }
+}
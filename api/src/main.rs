@@ -6,10 +6,11 @@ mod auth;
 mod db;
 mod error;
 mod graphql;
+mod provider;
 mod routes;
 mod spotify;
 
-use crate::db::DbHandler;
+use crate::{db::DbHandler, graphql::TagRules};
 use oauth2::{
     basic::BasicClient, AuthUrl, ClientId, ClientSecret, RedirectUrl, TokenUrl,
 };
@@ -29,6 +30,17 @@ pub struct LauludConfig {
     pub spotify_client_id: String,
     /// Secret for our Spotify app
     pub spotify_client_secret: String,
+    /// How long after a tag mutation it can still be undone, in seconds
+    #[serde(default = "default_undo_expiry_seconds")]
+    pub undo_expiry_seconds: u64,
+    /// Rules for normalizing and validating tags
+    #[serde(default)]
+    pub tags: TagRules,
+}
+
+fn default_undo_expiry_seconds() -> u64 {
+    // 10 minutes
+    600
 }
 
 /// Initialize the Spotify OAuth client. Any failures in here will cause a
@@ -62,7 +74,10 @@ async fn launch() -> Rocket<Build> {
     let rocket = rocket::build();
     let config: LauludConfig = rocket.figment().extract().unwrap();
 
+    // This has to happen before any tags are parsed, including in migrations
+    config.tags.clone().install();
     let db_handler = DbHandler::connect(&config).await.unwrap();
+    db_handler.run_migrations().await.unwrap();
     let spotify_oauth_client = init_spotify_client(&config).await;
     let graphql_schema = graphql::create_graphql_schema("./schema.graphql")
         .await
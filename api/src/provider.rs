@@ -0,0 +1,505 @@
+//! An abstraction over the catalogs that items can come from. Everything in
+//! the GraphQL layer talks to a [MusicProvider] rather than a specific API, and
+//! items are identified by an [ItemUri], which is qualified with the
+//! [Provider] that the item belongs to.
+//!
+//! Spotify is the only provider right now, so the types for item data (tracks,
+//! albums, etc.) are still the Spotify types. Adding another catalog (e.g. a
+//! local MusicBrainz dump) means adding a [Provider] variant, implementing
+//! [MusicProvider] for it, and adding it to the [ProviderRegistry] for each
+//! request.
+
+use crate::{
+    error::{ApiError, ApiResult, ParseError},
+    spotify::{
+        AlbumSimplified, AudioFeatures, Item, PaginatedResponse, PrivateUser,
+    },
+};
+use async_graphql::scalar;
+use async_trait::async_trait;
+use derive_more::Display;
+use futures::future::try_join_all;
+use itertools::Itertools;
+use mongodb::bson::Bson;
+use serde::{Deserialize, Serialize};
+use std::{
+    backtrace::Backtrace, collections::HashMap, convert::TryFrom, fmt::Debug,
+    future::Future, str::FromStr,
+};
+
+/// Everything the API needs from a music catalog. Each provider is only
+/// responsible for items that belong to it, i.e. URIs with its own [Provider].
+#[async_trait]
+pub trait MusicProvider: Debug + Send + Sync {
+    /// Get the user that's currently logged in to the provider
+    async fn get_current_user(&self) -> ApiResult<PrivateUser>;
+
+    /// Get a single item by URI. Returns `None` if the item doesn't exist.
+    async fn get_item(&self, uri: &ItemUri) -> ApiResult<Option<Item>>;
+
+    /// Get any number of items, of any type. Items that don't exist are left
+    /// out of the output, so it may be shorter than the input.
+    async fn get_items(&self, uris: &[&ItemUri]) -> ApiResult<Vec<Item>>;
+
+    /// Search among taggable items. Results are grouped by item type, keyed by
+    /// the plural name of the type (e.g. `tracks`). Podcast shows and episodes
+    /// are only included if `include_podcasts` is set.
+    async fn search_items(
+        &self,
+        search_query: &str,
+        limit: Option<usize>,
+        offset: Option<usize>,
+        include_podcasts: bool,
+    ) -> ApiResult<HashMap<String, PaginatedResponse<Item>>>;
+
+    /// Get audio analysis for a single track
+    async fn get_audio_features(
+        &self,
+        track_uri: &ItemUri,
+    ) -> ApiResult<AudioFeatures>;
+
+    /// Get audio analysis for any number of tracks. Tracks that don't have
+    /// any analysis are left out of the output.
+    async fn get_audio_features_bulk(
+        &self,
+        track_uris: &[&ItemUri],
+    ) -> ApiResult<Vec<AudioFeatures>>;
+
+    /// Get the URIs of every track on some albums
+    async fn get_album_track_uris(
+        &self,
+        album_uris: &[&ItemUri],
+    ) -> ApiResult<Vec<ItemUri>>;
+
+    /// Get the albums and singles released by an artist
+    async fn get_artist_albums(
+        &self,
+        artist_uri: &ItemUri,
+    ) -> ApiResult<PaginatedResponse<AlbumSimplified>>;
+}
+
+/// Every [MusicProvider] available to a request, keyed by [Provider]. Calls
+/// for particular items should go to the provider that each item belongs to
+/// (see [Self::for_uri] and [Self::call_grouped]), and anything else (e.g.
+/// search) goes to the primary provider, which is the one the user logged in
+/// with.
+#[derive(Debug)]
+pub struct ProviderRegistry {
+    primary: Provider,
+    providers: HashMap<Provider, Box<dyn MusicProvider>>,
+}
+
+impl ProviderRegistry {
+    /// Create a registry with just the primary provider
+    pub fn new(primary: Provider, provider: Box<dyn MusicProvider>) -> Self {
+        Self {
+            primary,
+            providers: HashMap::from([(primary, provider)]),
+        }
+    }
+
+    /// Get the provider that the user logged in with
+    pub fn primary(&self) -> ApiResult<&dyn MusicProvider> {
+        self.get(self.primary)
+    }
+
+    /// Get the provider that an item belongs to
+    pub fn for_uri(&self, uri: &ItemUri) -> ApiResult<&dyn MusicProvider> {
+        self.get(uri.provider())
+    }
+
+    /// Make a call for a group of URIs that may belong to different
+    /// providers. The URIs are split up by provider, each provider is called
+    /// with its own URIs, and all the results are concatenated together.
+    pub async fn call_grouped<'a, 'u, T, F, Fut>(
+        &'a self,
+        uris: impl IntoIterator<Item = &'u ItemUri>,
+        call: F,
+    ) -> ApiResult<Vec<T>>
+    where
+        F: Fn(&'a dyn MusicProvider, Vec<&'u ItemUri>) -> Fut,
+        Fut: Future<Output = ApiResult<Vec<T>>>,
+    {
+        let results = try_join_all(
+            self.group(uris)?
+                .into_iter()
+                .map(|(provider, uris)| call(provider, uris)),
+        )
+        .await?;
+        Ok(results.into_iter().flatten().collect())
+    }
+
+    /// Get the implementation for a provider
+    fn get(&self, provider: Provider) -> ApiResult<&dyn MusicProvider> {
+        self.providers
+            .get(&provider)
+            .map(|provider| &**provider)
+            .ok_or_else(|| ApiError::UnsupportedProvider {
+                provider,
+                backtrace: Backtrace::capture(),
+            })
+    }
+
+    /// Split up a group of URIs by the provider that each one belongs to
+    fn group<'u>(
+        &self,
+        uris: impl IntoIterator<Item = &'u ItemUri>,
+    ) -> ApiResult<Vec<(&dyn MusicProvider, Vec<&'u ItemUri>)>> {
+        uris.into_iter()
+            .map(|uri| (uri.provider(), uri))
+            .into_group_map()
+            .into_iter()
+            .map(|(provider, uris)| Ok((self.get(provider)?, uris)))
+            .collect()
+    }
+}
+
+/// A catalog that items can come from. This is the first segment of an
+/// [ItemUri].
+#[derive(Copy, Clone, Debug, Display, PartialEq, Eq, Hash)]
+pub enum Provider {
+    #[display(fmt = "spotify")]
+    Spotify,
+}
+
+impl FromStr for Provider {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "spotify" => Ok(Provider::Spotify),
+            _ => Err(ParseError {
+                message: "Unknown provider".into(),
+                value: s.into(),
+            }),
+        }
+    }
+}
+
+/// The type of an item. This is the middle segment of an [ItemUri]. Types are
+/// shared between providers, though a provider doesn't have to support every
+/// type.
+///
+/// Note: this doesn't include every type that a provider might have, just the
+/// ones we use. Add more as needed.
+#[derive(
+    Copy, Clone, Debug, Display, PartialEq, Eq, Hash, Serialize, Deserialize,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum ItemType {
+    #[display(fmt = "track")]
+    Track,
+    #[display(fmt = "album")]
+    Album,
+    #[display(fmt = "artist")]
+    Artist,
+    #[display(fmt = "playlist")]
+    Playlist,
+    #[display(fmt = "show")]
+    Show,
+    #[display(fmt = "episode")]
+    Episode,
+    #[display(fmt = "user")]
+    User,
+}
+
+impl FromStr for ItemType {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "track" => Ok(ItemType::Track),
+            "album" => Ok(ItemType::Album),
+            "artist" => Ok(ItemType::Artist),
+            "playlist" => Ok(ItemType::Playlist),
+            "show" => Ok(ItemType::Show),
+            "episode" => Ok(ItemType::Episode),
+            "user" => Ok(ItemType::User),
+            _ => Err(ParseError {
+                message: "Unknown item type".into(),
+                value: s.into(),
+            }),
+        }
+    }
+}
+
+/// A parsed and validated item URI, of the format
+/// `<provider>:<type>:<id>`. A URI uniquely identifies an item across all
+/// providers, and also includes its type. Note that in this context, "valid"
+/// just means it's not _malformed_. It **doesn't** mean that the URI actually
+/// exists in the provider's catalog.
+///
+/// For Spotify, this is the same as a Spotify URI. Besides the canonical
+/// `spotify:<type>:<id>` form, this can be parsed from an open.spotify.com
+/// link (e.g. a share link, or an embed URL). Either way, it's always stored
+/// and displayed in the canonical form.
+#[derive(
+    Clone, Debug, Display, PartialEq, Eq, Hash, Serialize, Deserialize,
+)]
+#[display(fmt = "{}:{}:{}", provider, item_type, id)]
+#[serde(try_from = "String", into = "String")]
+pub struct ItemUri {
+    provider: Provider,
+    item_type: ItemType,
+    id: String,
+}
+
+impl ItemUri {
+    pub fn provider(&self) -> Provider {
+        self.provider
+    }
+
+    pub fn item_type(&self) -> ItemType {
+        self.item_type
+    }
+
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// Build a URI from its type and ID, validating both
+    fn from_parts(
+        provider: Provider,
+        item_type: &str,
+        id: &str,
+    ) -> Result<Self, String> {
+        // It's possible we get a valid item type that we just don't support,
+        // just going to treat those as invalid for now.
+        let item_type = item_type.parse::<ItemType>().map_err(|_| {
+            format!("Invalid item URI: unknown item type {}", item_type)
+        })?;
+        let valid_id = match provider {
+            // Item IDs are always base62. User IDs are just usernames though,
+            // so they can contain pretty much anything.
+            Provider::Spotify => {
+                !id.is_empty()
+                    && (item_type == ItemType::User
+                        || id.chars().all(|c| c.is_ascii_alphanumeric()))
+            }
+        };
+        if !valid_id {
+            return Err(format!("Invalid item URI: invalid ID {}", id));
+        }
+        Ok(Self {
+            provider,
+            item_type,
+            id: id.into(),
+        })
+    }
+
+    /// Parse an open.spotify.com URL, e.g.
+    /// `https://open.spotify.com/intl-de/track/<id>?si=...`. The scheme,
+    /// locale prefix, embed prefix and query string are all optional.
+    fn from_spotify_url(value: &str) -> Result<Self, String> {
+        let without_scheme = value
+            .strip_prefix("https://")
+            .or_else(|| value.strip_prefix("http://"))
+            .unwrap_or(value);
+        let path = without_scheme
+            .strip_prefix("open.spotify.com/")
+            .ok_or_else(|| String::from("Invalid item URI: invalid format"))?;
+        // Drop the query string and fragment, e.g. the `?si=` share tracker
+        let path = path.split(|c| c == '?' || c == '#').next().unwrap_or("");
+
+        let mut segments = path
+            .split('/')
+            .filter(|segment| !segment.is_empty())
+            .peekable();
+        segments.next_if(|segment| segment.starts_with("intl-"));
+        segments.next_if(|segment| {
+            *segment == "embed" || *segment == "embed-podcast"
+        });
+        match segments.collect::<Vec<&str>>().as_slice() {
+            [item_type, id] => {
+                Self::from_parts(Provider::Spotify, item_type, id)
+            }
+            _ => Err("Invalid item URI: invalid format".into()),
+        }
+    }
+}
+
+// Declare this as a GraphQL scalar
+scalar!(ItemUri);
+
+// These two impls needed for serde
+impl From<ItemUri> for String {
+    fn from(uri: ItemUri) -> Self {
+        uri.to_string()
+    }
+}
+impl TryFrom<String> for ItemUri {
+    type Error = <ItemUri as FromStr>::Err;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+// Needed for the Interface derive on Item
+impl From<&Self> for ItemUri {
+    fn from(value: &Self) -> Self {
+        value.clone()
+    }
+}
+
+// For DB interactions
+impl From<&ItemUri> for Bson {
+    fn from(uri: &ItemUri) -> Self {
+        uri.to_string().into()
+    }
+}
+
+impl FromStr for ItemUri {
+    type Err = ParseError;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        // Expect either URIs of the format "<provider>:<type>:<id>", or a
+        // Spotify URL
+        // We have to generate errors as strings first, then map to a proper
+        // error type, cause borrowck
+        let trimmed = value.trim();
+        // Check for a URL first, since URLs can contain colons too (e.g. in
+        // the query string)
+        let is_url =
+            trimmed.contains("://") || trimmed.starts_with("open.spotify.com/");
+        let parsed: Result<ItemUri, String> = if is_url {
+            Self::from_spotify_url(trimmed)
+        } else {
+            match trimmed.split(':').collect::<Vec<&str>>().as_slice() {
+                [provider, item_type, id] => match provider.parse() {
+                    Ok(provider) => Self::from_parts(provider, item_type, id),
+                    Err(_) => Err(format!(
+                        "Invalid item URI: unknown provider {}",
+                        provider
+                    )),
+                },
+                _ => Err("Invalid item URI: invalid format".into()),
+            }
+        };
+        parsed.map_err(|message| ParseError {
+            message,
+            value: value.into(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(value: &str) -> Result<String, String> {
+        value
+            .parse::<ItemUri>()
+            .map(|uri| uri.to_string())
+            .map_err(|err| err.message)
+    }
+
+    #[test]
+    fn test_uri() {
+        assert_eq!(
+            parse("spotify:track:6rqhFgbbKwnb9MLmUQDhG6"),
+            Ok("spotify:track:6rqhFgbbKwnb9MLmUQDhG6".into())
+        );
+        assert_eq!(
+            parse("  spotify:album:abc123\n"),
+            Ok("spotify:album:abc123".into())
+        );
+        // User IDs are usernames, so they aren't restricted to base62
+        assert_eq!(
+            parse("spotify:user:some.user-name"),
+            Ok("spotify:user:some.user-name".into())
+        );
+    }
+
+    #[test]
+    fn test_uri_invalid() {
+        assert_eq!(
+            parse("deezer:track:abc123"),
+            Err("Invalid item URI: unknown provider deezer".into())
+        );
+        assert_eq!(
+            parse("spotify:song:abc123"),
+            Err("Invalid item URI: unknown item type song".into())
+        );
+        assert_eq!(
+            parse("spotify:track:"),
+            Err("Invalid item URI: invalid ID ".into())
+        );
+        assert_eq!(
+            parse("spotify:track:abc-123"),
+            Err("Invalid item URI: invalid ID abc-123".into())
+        );
+        assert_eq!(
+            parse("spotify:track:abc123:extra"),
+            Err("Invalid item URI: invalid format".into())
+        );
+        assert_eq!(parse(""), Err("Invalid item URI: invalid format".into()));
+    }
+
+    #[test]
+    fn test_url() {
+        let expected = Ok("spotify:track:abc123".into());
+        assert_eq!(parse("https://open.spotify.com/track/abc123"), expected);
+        assert_eq!(parse("http://open.spotify.com/track/abc123"), expected);
+        assert_eq!(parse("open.spotify.com/track/abc123"), expected);
+        assert_eq!(parse("https://open.spotify.com/track/abc123/"), expected);
+        // Share trackers and fragments are dropped
+        assert_eq!(
+            parse("https://open.spotify.com/track/abc123?si=x1y2z3"),
+            expected
+        );
+        assert_eq!(parse("https://open.spotify.com/track/abc123#x"), expected);
+        // Colons in the query string don't get mistaken for a URI
+        assert_eq!(
+            parse("https://open.spotify.com/track/abc123?si=a:b"),
+            expected
+        );
+        assert_eq!(parse("open.spotify.com/track/abc123?si=a:b:c"), expected);
+        // Locale and embed prefixes are skipped
+        assert_eq!(
+            parse("https://open.spotify.com/intl-de/track/abc123"),
+            expected
+        );
+        assert_eq!(
+            parse("https://open.spotify.com/embed/track/abc123"),
+            expected
+        );
+        assert_eq!(
+            parse("https://open.spotify.com/intl-pt/embed/track/abc123"),
+            expected
+        );
+        assert_eq!(
+            parse("https://open.spotify.com/embed-podcast/episode/abc123"),
+            Ok("spotify:episode:abc123".into())
+        );
+    }
+
+    #[test]
+    fn test_url_invalid() {
+        assert_eq!(
+            parse("https://example.com/track/abc123"),
+            Err("Invalid item URI: invalid format".into())
+        );
+        assert_eq!(
+            parse("https://open.spotify.com/track"),
+            Err("Invalid item URI: invalid format".into())
+        );
+        assert_eq!(
+            parse("https://open.spotify.com/track/abc123/extra"),
+            Err("Invalid item URI: invalid format".into())
+        );
+        // Prefixes are only skipped in the right order
+        assert_eq!(
+            parse("https://open.spotify.com/embed/intl-de/track/abc123"),
+            Err("Invalid item URI: invalid format".into())
+        );
+        assert_eq!(
+            parse("https://open.spotify.com/song/abc123"),
+            Err("Invalid item URI: unknown item type song".into())
+        );
+        assert_eq!(
+            parse("https://open.spotify.com/track/abc-123"),
+            Err("Invalid item URI: invalid ID abc-123".into())
+        );
+    }
+}
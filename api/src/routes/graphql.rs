@@ -1,7 +1,8 @@
 use crate::{
     auth::UserId,
     db::DbHandler,
-    graphql::{GraphQLSchema, RequestContext},
+    graphql::{GraphQLSchema, RequestCache, RequestContext},
+    provider::{Provider, ProviderRegistry},
     spotify::Spotify,
 };
 use async_graphql::http::GraphiQLSource;
@@ -31,8 +32,12 @@ pub async fn route_graphql(
         // little contexts, so we can get the benefits of static typing
         .data(RequestContext {
             db_handler: Arc::clone(db_handler.inner()),
-            spotify,
+            providers: ProviderRegistry::new(
+                Provider::Spotify,
+                Box::new(spotify),
+            ),
             user_id,
+            cache: RequestCache::default(),
         })
         .execute(graphql_schema)
         .await
@@ -13,8 +13,10 @@ pub use types::*;
 use crate::{
     auth::{IdentityState, OAuthHandler},
     error::{ApiError, ApiResult},
+    provider::{ItemType, ItemUri, MusicProvider},
 };
-use futures::future::try_join_all;
+use async_trait::async_trait;
+use futures::{future::try_join_all, stream, StreamExt, TryStreamExt};
 use itertools::Itertools;
 use log::{debug, trace};
 use oauth2::basic::BasicClient;
@@ -30,6 +32,32 @@ use serde::{de::DeserializeOwned, Serialize};
 use std::{backtrace::Backtrace, collections::HashMap, sync::Arc};
 
 const SPOTIFY_BASE_URL: &str = "https://api.spotify.com";
+/// https://developer.spotify.com/documentation/web-api/reference/#/operations/get-several-audio-features
+const MAX_AUDIO_FEATURES_PER_REQUEST: usize = 100;
+/// Playlists have to be fetched one at a time, so this is how many of those
+/// requests can run at once
+const MAX_CONCURRENT_PLAYLIST_REQUESTS: usize = 5;
+/// The fields we request when fetching a single playlist. Without this, Spotify
+/// includes every track in the playlist, which we don't need. These line up
+/// with the simplified playlist object, which is what [Playlist] holds.
+const PLAYLIST_FIELDS: &str = "collaborative,description,external_urls,href,\
+    id,images,name,owner,public,snapshot_id,tracks(href,total),type,uri";
+
+/// Get the maximum number of IDs that Spotify accepts in a single request to
+/// one of the "get several" endpoints, for a particular item type
+fn max_ids_per_request(item_type: ItemType) -> usize {
+    match item_type {
+        // https://developer.spotify.com/documentation/web-api/reference/#/operations/get-multiple-albums
+        ItemType::Album => 20,
+        // Playlists have to be fetched one at a time anyway. Keep them all in
+        // one group, so that MAX_CONCURRENT_PLAYLIST_REQUESTS applies to all
+        // of them together.
+        ItemType::Playlist => usize::MAX,
+        // Tracks, artists, shows and episodes all allow 50. Anything else is
+        // unsupported, so the number doesn't matter for those.
+        _ => 50,
+    }
+}
 
 /// A client for accessing the Spotify web API
 #[derive(Debug)]
@@ -155,18 +183,85 @@ impl Spotify {
         .await
     }
 
-    /// Search restricted to taggable items
+    /// Get the URIs of the tracks on any number of albums. The IDs will be
+    /// split into as many requests as needed, which will run concurrently.
+    /// Only the first page of tracks (50) is included for each album, which
+    /// covers all but the longest compilations.
+    pub async fn get_album_track_uris(
+        &self,
+        album_ids: &[&str],
+    ) -> ApiResult<Vec<ItemUri>> {
+        let futures = album_ids
+            .chunks(max_ids_per_request(ItemType::Album))
+            .map(|chunk| {
+                self.get_endpoint::<_, AlbumTracksResponse>(
+                    "/v1/albums",
+                    [("ids", chunk.iter().join(","))],
+                )
+            });
+        let responses = try_join_all(futures).await?;
+        Ok(responses
+            .into_iter()
+            .flat_map(|response| response.albums)
+            .flatten()
+            .flat_map(|album| album.tracks.items)
+            .map(|track| track.uri)
+            .collect())
+    }
+
+    /// Get the first page (50) of an artist's albums and singles. Appearances
+    /// on other artists' albums aren't included.
+    ///
+    /// https://developer.spotify.com/documentation/web-api/reference/#/operations/get-an-artists-albums
+    pub async fn get_artist_albums(
+        &self,
+        artist_id: &str,
+    ) -> ApiResult<PaginatedResponse<AlbumSimplified>> {
+        self.get_endpoint(
+            &format!("/v1/artists/{}/albums", artist_id),
+            &[("include_groups", "album,single"), ("limit", "50")],
+        )
+        .await
+    }
+
+    /// https://developer.spotify.com/documentation/web-api/reference/#/operations/get-multiple-shows
+    pub async fn get_shows(
+        &self,
+        mut show_ids: impl Iterator<Item = &str>,
+    ) -> ApiResult<ShowsResponse> {
+        self.get_endpoint("/v1/shows", &[("ids", show_ids.join(",").as_str())])
+            .await
+    }
+
+    /// https://developer.spotify.com/documentation/web-api/reference/#/operations/get-multiple-episodes
+    pub async fn get_episodes(
+        &self,
+        mut episode_ids: impl Iterator<Item = &str>,
+    ) -> ApiResult<EpisodesResponse> {
+        self.get_endpoint(
+            "/v1/episodes",
+            &[("ids", episode_ids.join(",").as_str())],
+        )
+        .await
+    }
+
+    /// Search restricted to taggable items. Podcast shows and episodes are
+    /// only included if `include_podcasts` is set, since most searches are
+    /// for music.
     /// https://developer.spotify.com/documentation/web-api/reference/#category-search
     pub async fn search_items(
         &self,
         search_query: &str,
         limit: Option<usize>,
         offset: Option<usize>,
+        include_podcasts: bool,
     ) -> ApiResult<HashMap<String, PaginatedResponse<Item>>> {
-        let mut query_params = vec![
-            ("q", search_query.to_owned()),
-            ("type", "track,album,artist".to_owned()),
-        ];
+        let mut item_types = "track,album,artist,playlist".to_owned();
+        if include_podcasts {
+            item_types.push_str(",show,episode");
+        }
+        let mut query_params =
+            vec![("q", search_query.to_owned()), ("type", item_types)];
 
         if let Some(limit) = limit {
             query_params.push(("limit", limit.to_string()));
@@ -175,9 +270,14 @@ impl Spotify {
             query_params.push(("offset", offset.to_string()));
         }
 
-        let responses: HashMap<String, PaginatedResponse<Item>> =
+        // Spotify sometimes returns null in place of an item (usually a
+        // playlist), so drop those rather than failing the whole search
+        let responses: HashMap<String, PaginatedResponse<Option<Item>>> =
             self.get_endpoint("/v1/search", &query_params).await?;
-        Ok(responses)
+        Ok(responses
+            .into_iter()
+            .map(|(item_type, response)| (item_type, response.flatten()))
+            .collect())
     }
 
     /// Get an item of any type from the API. This will call the correct
@@ -185,56 +285,75 @@ impl Spotify {
     /// iff the URI does not exist in Spotify (i.e. Spotify returned a 404).
     /// This makes the method more usable in GraphQL, where missing resources
     /// are typically returned as null.
-    pub async fn get_item(&self, uri: &SpotifyUri) -> ApiResult<Option<Item>> {
+    pub async fn get_item(&self, uri: &ItemUri) -> ApiResult<Option<Item>> {
         let result = match uri.item_type() {
             // https://developer.spotify.com/documentation/web-api/reference/tracks/get-track/
-            SpotifyItemType::Track => self
+            ItemType::Track => self
                 .get_endpoint::<&[&str], Track>(
                     &format!("/v1/tracks/{}", uri.id()),
                     &[],
                 )
-                .await.map(Item::from),
+                .await
+                .map(Item::from),
             // https://developer.spotify.com/documentation/web-api/reference/albums/get-album/
-            SpotifyItemType::Album => self
+            ItemType::Album => self
                 .get_endpoint::<&[&str], AlbumSimplified>(
                     &format!("/v1/albums/{}", uri.id()),
                     &[],
                 )
-                .await.map(Item::from),
+                .await
+                .map(Item::from),
             // https://developer.spotify.com/documentation/web-api/reference/artists/get-artist/
-            SpotifyItemType::Artist => self
+            ItemType::Artist => self
                 .get_endpoint::<&[&str], Artist>(
                     &format!("/v1/artists/{}", uri.id()),
                     &[],
                 )
                 .await
-                .map(Item::from)
-                ,
-            // We don't support tagging any other object types
-            item_type => {
-                 Err(ApiError::UnsupportedItemType {
-                    item_type,
-                    backtrace: Backtrace::capture(),
-                })
+                .map(Item::from),
+            ItemType::Playlist => {
+                self.get_playlist(uri.id()).await.map(Item::from)
             }
+            // https://developer.spotify.com/documentation/web-api/reference/#/operations/get-a-show
+            ItemType::Show => self
+                .get_endpoint::<&[&str], Show>(
+                    &format!("/v1/shows/{}", uri.id()),
+                    &[],
+                )
+                .await
+                .map(Item::from),
+            // https://developer.spotify.com/documentation/web-api/reference/#/operations/get-an-episode
+            ItemType::Episode => self
+                .get_endpoint::<&[&str], Episode>(
+                    &format!("/v1/episodes/{}", uri.id()),
+                    &[],
+                )
+                .await
+                .map(Item::from),
+            // We don't support tagging any other object types
+            item_type => Err(ApiError::UnsupportedItemType {
+                item_type,
+                backtrace: Backtrace::capture(),
+            }),
         };
 
-        match result {
-            Ok(item) => Ok(Some(item)),
-            // Map 404 to None
-            Err(ApiError::SpotifyApiHttp { source, .. })
-                if source.status().map(|s| s.as_u16()) == Some(404) =>
-            {
-                Ok(None)
-            }
-            Err(err) => Err(err),
-        }
+        not_found_to_none(result)
+    }
+
+    /// https://developer.spotify.com/documentation/web-api/reference/#/operations/get-playlist
+    pub async fn get_playlist(&self, playlist_id: &str) -> ApiResult<Playlist> {
+        self.get_endpoint(
+            &format!("/v1/playlists/{}", playlist_id),
+            &[("fields", PLAYLIST_FIELDS)],
+        )
+        .await
     }
 
     /// Fetch data for a list of items of any type. This will make one request
     /// to the API per item _type_ in the input list, e.g. if your input URIs
     /// have 3 tracks, 2 albums, and 10 artists, this will still only make 3
-    /// requests to the API.
+    /// requests to the API. Spotify caps the number of IDs per request though,
+    /// so large lists will be split across additional requests.
     ///
     /// If any of the given URIs doesn't return a response from Spotify, then
     /// that item will simply not be included in the output. So the output will
@@ -242,10 +361,10 @@ impl Spotify {
     /// invalid/non-matching URIs.
     pub async fn get_items(
         &self,
-        uris: impl Iterator<Item = &SpotifyUri>,
+        uris: impl Iterator<Item = &ItemUri>,
     ) -> ApiResult<Vec<Item>> {
         // Group URIs by type so we can make one request per type
-        let ids_by_type: HashMap<SpotifyItemType, Vec<&str>> =
+        let ids_by_type: HashMap<ItemType, Vec<&str>> =
             uris.map(|uri| (uri.item_type(), uri.id())).into_group_map();
 
         /// Convert a list of search results of any type into a standardized
@@ -266,29 +385,59 @@ impl Spotify {
                 .collect::<Vec<_>>()
         }
 
-        // Make one request to the Spotify API for each item type. These will
-        // run concurrently, hence the try_join_all down below
-        let futures =
-            ids_by_type.into_iter().map(|(item_type, ids)| async move {
+        // Make one request to the Spotify API for each item type (or more, if
+        // there are too many IDs of one type). These will run concurrently,
+        // hence the try_join_all down below
+        let futures = ids_by_type
+            .into_iter()
+            .flat_map(|(item_type, ids)| {
+                ids.chunks(max_ids_per_request(item_type))
+                    .map(|chunk| (item_type, chunk.to_vec()))
+                    .collect::<Vec<_>>()
+            })
+            .map(|(item_type, ids)| async move {
                 // Shortcut!
                 if ids.is_empty() {
                     return Ok(Vec::new());
                 }
 
                 match item_type {
-                    SpotifyItemType::Track => {
+                    ItemType::Track => {
                         let response = self.get_tracks(ids.into_iter()).await?;
                         Ok(results_to_items(response.tracks))
                     }
-                    SpotifyItemType::Album => {
+                    ItemType::Album => {
                         let response = self.get_albums(ids.into_iter()).await?;
                         Ok(results_to_items(response.albums))
                     }
-                    SpotifyItemType::Artist => {
+                    ItemType::Artist => {
                         let response =
                             self.get_artists(ids.into_iter()).await?;
                         Ok(results_to_items(response.artists))
                     }
+                    // There's no endpoint to get several playlists at once, so
+                    // fetch them one at a time (a few concurrently). Missing
+                    // playlists are skipped, like with the other types.
+                    ItemType::Playlist => {
+                        let playlists: Vec<Option<Playlist>> = stream::iter(
+                            ids.into_iter().map(|id| async move {
+                                not_found_to_none(self.get_playlist(id).await)
+                            }),
+                        )
+                        .buffer_unordered(MAX_CONCURRENT_PLAYLIST_REQUESTS)
+                        .try_collect()
+                        .await?;
+                        Ok(results_to_items(playlists))
+                    }
+                    ItemType::Show => {
+                        let response = self.get_shows(ids.into_iter()).await?;
+                        Ok(results_to_items(response.shows))
+                    }
+                    ItemType::Episode => {
+                        let response =
+                            self.get_episodes(ids.into_iter()).await?;
+                        Ok(results_to_items(response.episodes))
+                    }
                     _ => Err(ApiError::UnsupportedItemType {
                         item_type,
                         backtrace: Backtrace::capture(),
@@ -315,6 +464,114 @@ impl Spotify {
         )
         .await
     }
+
+    /// https://developer.spotify.com/documentation/web-api/reference/#/operations/get-several-audio-features
+    pub async fn get_several_audio_features(
+        &self,
+        mut track_ids: impl Iterator<Item = &str>,
+    ) -> ApiResult<AudioFeaturesResponse> {
+        self.get_endpoint(
+            "/v1/audio-features",
+            &[("ids", track_ids.join(",").as_str())],
+        )
+        .await
+    }
+
+    /// Get audio features for any number of tracks. The IDs will be split
+    /// into as many requests as needed, which will run concurrently. Tracks
+    /// that Spotify doesn't have features for are left out of the output.
+    pub async fn get_audio_features_bulk(
+        &self,
+        track_ids: &[&str],
+    ) -> ApiResult<Vec<AudioFeatures>> {
+        let futures =
+            track_ids
+                .chunks(MAX_AUDIO_FEATURES_PER_REQUEST)
+                .map(|chunk| {
+                    self.get_several_audio_features(chunk.iter().copied())
+                });
+        let responses = try_join_all(futures).await?;
+        Ok(responses
+            .into_iter()
+            .flat_map(|response| response.audio_features)
+            .flatten()
+            .collect())
+    }
+}
+
+// The provider interface just delegates to the methods above, which are kept
+// inherent so they can take more flexible arguments, and Spotify IDs rather
+// than provider-qualified URIs
+#[async_trait]
+impl MusicProvider for Spotify {
+    async fn get_current_user(&self) -> ApiResult<PrivateUser> {
+        self.get_current_user().await
+    }
+
+    async fn get_item(&self, uri: &ItemUri) -> ApiResult<Option<Item>> {
+        self.get_item(uri).await
+    }
+
+    async fn get_items(&self, uris: &[&ItemUri]) -> ApiResult<Vec<Item>> {
+        self.get_items(uris.iter().copied()).await
+    }
+
+    async fn search_items(
+        &self,
+        search_query: &str,
+        limit: Option<usize>,
+        offset: Option<usize>,
+        include_podcasts: bool,
+    ) -> ApiResult<HashMap<String, PaginatedResponse<Item>>> {
+        self.search_items(search_query, limit, offset, include_podcasts)
+            .await
+    }
+
+    async fn get_audio_features(
+        &self,
+        track_uri: &ItemUri,
+    ) -> ApiResult<AudioFeatures> {
+        self.get_audio_features(track_uri.id()).await
+    }
+
+    async fn get_audio_features_bulk(
+        &self,
+        track_uris: &[&ItemUri],
+    ) -> ApiResult<Vec<AudioFeatures>> {
+        let track_ids: Vec<&str> =
+            track_uris.iter().map(|uri| uri.id()).collect();
+        self.get_audio_features_bulk(&track_ids).await
+    }
+
+    async fn get_album_track_uris(
+        &self,
+        album_uris: &[&ItemUri],
+    ) -> ApiResult<Vec<ItemUri>> {
+        let album_ids: Vec<&str> =
+            album_uris.iter().map(|uri| uri.id()).collect();
+        self.get_album_track_uris(&album_ids).await
+    }
+
+    async fn get_artist_albums(
+        &self,
+        artist_uri: &ItemUri,
+    ) -> ApiResult<PaginatedResponse<AlbumSimplified>> {
+        self.get_artist_albums(artist_uri.id()).await
+    }
+}
+
+/// Map a 404 response from Spotify to `None`, so that missing resources can be
+/// returned as null in GraphQL. Any other error is passed through.
+fn not_found_to_none<T>(result: ApiResult<T>) -> ApiResult<Option<T>> {
+    match result {
+        Ok(value) => Ok(Some(value)),
+        Err(ApiError::SpotifyApiHttp { source, .. })
+            if source.status().map(|s| s.as_u16()) == Some(404) =>
+        {
+            Ok(None)
+        }
+        Err(err) => Err(err),
+    }
 }
 
 // Make it easy to grab a spotify instance for any request handler
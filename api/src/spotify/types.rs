@@ -2,14 +2,11 @@
 //! relate closely to the Spotify API. Everything in this module will be
 //! exported to the entire crate!
 
-use crate::{error::ParseError, graphql::RequestContext};
+use crate::{graphql::RequestContext, provider::ItemUri};
 use async_graphql::{
-    scalar, ComplexObject, Context, FieldResult, Interface, SimpleObject,
+    ComplexObject, Context, FieldResult, Interface, SimpleObject,
 };
-use derive_more::Display;
-use mongodb::bson::Bson;
-use serde::{Deserialize, Serialize};
-use std::{convert::TryFrom, str::FromStr};
+use serde::Deserialize;
 
 /// https://developer.spotify.com/documentation/web-api/reference/object-model/#artist-object-simplified
 #[derive(Clone, Debug, Deserialize, SimpleObject)]
@@ -18,7 +15,7 @@ pub struct ArtistSimplified {
     pub href: String,
     pub id: String,
     pub name: String,
-    pub uri: SpotifyUri,
+    pub uri: ItemUri,
 }
 
 /// https://developer.spotify.com/documentation/web-api/reference/object-model/#artist-object-full
@@ -31,7 +28,7 @@ pub struct Artist {
     pub images: Vec<Image>,
     pub name: String,
     pub popularity: i32,
-    pub uri: SpotifyUri,
+    pub uri: ItemUri,
 }
 
 /// https://developer.spotify.com/documentation/web-api/reference/object-model/#album-object-simplified
@@ -48,7 +45,7 @@ pub struct AlbumSimplified {
     pub name: String,
     pub release_date: String,
     pub release_date_precision: String,
-    pub uri: SpotifyUri,
+    pub uri: ItemUri,
 }
 
 /// https://developer.spotify.com/documentation/web-api/reference/#object-audiofeaturesobject
@@ -69,7 +66,7 @@ pub struct AudioFeatures {
     pub tempo: f64,
     pub time_signature: i32,
     pub track_href: String,
-    pub uri: SpotifyUri,
+    pub uri: ItemUri,
     pub valence: f64,
 }
 
@@ -91,7 +88,7 @@ pub struct Track {
     pub popularity: i32,
     pub preview_url: Option<String>,
     pub track_number: i32,
-    pub uri: SpotifyUri,
+    pub uri: ItemUri,
 }
 
 #[ComplexObject]
@@ -102,7 +99,11 @@ impl Track {
         context: &Context<'_>,
     ) -> FieldResult<AudioFeatures> {
         let context = context.data::<RequestContext>()?;
-        Ok(context.spotify.get_audio_features(&self.id).await?)
+        Ok(context
+            .providers
+            .for_uri(&self.uri)?
+            .get_audio_features(&self.uri)
+            .await?)
     }
 }
 
@@ -127,12 +128,87 @@ pub struct Image {
     pub height: Option<i32>,
 }
 
+/// https://developer.spotify.com/documentation/web-api/reference/#object-simplifiedplaylistobject
+/// The full playlist object includes a lot more data (e.g. every track), but
+/// when fetching a single playlist we only request the fields in this struct,
+/// so both can be parsed the same way.
+#[derive(Clone, Debug, Deserialize, SimpleObject)]
+pub struct Playlist {
+    pub collaborative: bool,
+    pub description: Option<String>,
+    pub external_urls: ExternalUrls,
+    pub href: String,
+    pub id: String,
+    pub images: Vec<Image>,
+    pub name: String,
+    pub owner: PublicUser,
+    pub public: Option<bool>,
+    pub snapshot_id: String,
+    pub tracks: PlaylistTracksRef,
+    pub uri: ItemUri,
+}
+
+/// https://developer.spotify.com/documentation/web-api/reference/#object-playlisttracksrefobject
+#[derive(Clone, Debug, Deserialize, SimpleObject)]
+pub struct PlaylistTracksRef {
+    pub href: String,
+    pub total: i32,
+}
+
+/// https://developer.spotify.com/documentation/web-api/reference/#object-publicuserobject
+#[derive(Clone, Debug, Deserialize, SimpleObject)]
+pub struct PublicUser {
+    pub display_name: Option<String>,
+    pub external_urls: ExternalUrls,
+    pub href: String,
+    pub id: String,
+    pub uri: ItemUri,
+}
+
+/// https://developer.spotify.com/documentation/web-api/reference/#object-simplifiedshowobject
+#[derive(Clone, Debug, Deserialize, SimpleObject)]
+pub struct Show {
+    pub description: String,
+    pub explicit: bool,
+    pub external_urls: ExternalUrls,
+    pub href: String,
+    pub id: String,
+    pub images: Vec<Image>,
+    pub languages: Vec<String>,
+    pub media_type: String,
+    pub name: String,
+    pub publisher: String,
+    pub uri: ItemUri,
+}
+
+/// https://developer.spotify.com/documentation/web-api/reference/#object-episodeobject
+#[derive(Clone, Debug, Deserialize, SimpleObject)]
+pub struct Episode {
+    pub audio_preview_url: Option<String>,
+    pub description: String,
+    pub duration_ms: i32,
+    pub explicit: bool,
+    pub external_urls: ExternalUrls,
+    pub href: String,
+    pub id: String,
+    pub images: Vec<Image>,
+    pub is_playable: Option<bool>,
+    pub languages: Vec<String>,
+    pub name: String,
+    pub release_date: String,
+    pub release_date_precision: String,
+    /// The show that this episode belongs to. Search results don't include
+    /// this, so it'll be null there.
+    pub show: Option<Show>,
+    pub uri: ItemUri,
+}
+
 /// https://developer.spotify.com/documentation/web-api/reference/#object-privateuserobject
 #[derive(Clone, Debug, Deserialize, SimpleObject)]
 pub struct PrivateUser {
     pub id: String,
     pub href: String,
-    pub uri: SpotifyUri,
+    pub uri: ItemUri,
     pub display_name: Option<String>,
     pub images: Vec<Image>,
 }
@@ -151,12 +227,51 @@ pub struct AlbumsResponse {
     pub albums: Vec<Option<AlbumSimplified>>,
 }
 
+/// The same response as [AlbumsResponse], but only the track listing for each
+/// album is kept
+#[derive(Clone, Debug, Deserialize)]
+pub struct AlbumTracksResponse {
+    pub albums: Vec<Option<AlbumTracks>>,
+}
+
+/// The URI and track listing of a full album object. Spotify only includes
+/// the first page of tracks here.
+#[derive(Clone, Debug, Deserialize)]
+pub struct AlbumTracks {
+    pub uri: ItemUri,
+    pub tracks: PaginatedResponse<ItemReference>,
+}
+
+/// Any Spotify object, where all we care about is the URI
+#[derive(Clone, Debug, Deserialize)]
+pub struct ItemReference {
+    pub uri: ItemUri,
+}
+
 /// https://developer.spotify.com/documentation/web-api/reference/artists/get-several-artists/
 #[derive(Clone, Debug, Deserialize)]
 pub struct ArtistsResponse {
     pub artists: Vec<Option<Artist>>,
 }
 
+/// https://developer.spotify.com/documentation/web-api/reference/#/operations/get-multiple-shows
+#[derive(Clone, Debug, Deserialize)]
+pub struct ShowsResponse {
+    pub shows: Vec<Option<Show>>,
+}
+
+/// https://developer.spotify.com/documentation/web-api/reference/#/operations/get-multiple-episodes
+#[derive(Clone, Debug, Deserialize)]
+pub struct EpisodesResponse {
+    pub episodes: Vec<Option<Episode>>,
+}
+
+/// https://developer.spotify.com/documentation/web-api/reference/#/operations/get-several-audio-features
+#[derive(Clone, Debug, Deserialize)]
+pub struct AudioFeaturesResponse {
+    pub audio_features: Vec<Option<AudioFeatures>>,
+}
+
 /// https://developer.spotify.com/documentation/web-api/reference/#category-search
 /// The search method is hard-coded to always request these item categories,
 /// so we can hard-code them here. If we wanted to make that dynamic though, we
@@ -166,6 +281,12 @@ pub struct SearchResponse {
     pub tracks: PaginatedResponse<Track>,
     pub albums: PaginatedResponse<AlbumSimplified>,
     pub artists: PaginatedResponse<Artist>,
+    /// Spotify sometimes returns null in place of a playlist
+    pub playlists: PaginatedResponse<Option<Playlist>>,
+    /// Only included if podcasts were requested
+    pub shows: Option<PaginatedResponse<Show>>,
+    /// Only included if podcasts were requested
+    pub episodes: Option<PaginatedResponse<Episode>>,
 }
 
 /// https://developer.spotify.com/documentation/web-api/reference/object-model/#paging-object
@@ -180,129 +301,23 @@ pub struct PaginatedResponse<T> {
     pub items: Vec<T>,
 }
 
-/// Any item type that can get a URI
-///
-/// Note: we don't actually support every Spotify type here yet, just the ones
-/// we use. Add more as needed.
-#[derive(
-    Copy, Clone, Debug, Display, PartialEq, Eq, Hash, Serialize, Deserialize,
-)]
-#[serde(rename_all = "lowercase")]
-pub enum SpotifyItemType {
-    #[display(fmt = "track")]
-    Track,
-    #[display(fmt = "album")]
-    Album,
-    #[display(fmt = "artist")]
-    Artist,
-    #[display(fmt = "user")]
-    User,
-}
-
-impl FromStr for SpotifyItemType {
-    type Err = ParseError;
-
-    fn from_str(s: &str) -> Result<Self, Self::Err> {
-        match s {
-            "track" => Ok(SpotifyItemType::Track),
-            "album" => Ok(SpotifyItemType::Album),
-            "artist" => Ok(SpotifyItemType::Artist),
-            "user" => Ok(SpotifyItemType::User),
-            _ => Err(ParseError {
-                message: "Unknown Spotify object type".into(),
-                value: s.into(),
-            }),
+impl<T> PaginatedResponse<Option<T>> {
+    /// Drop any null items from the page. Spotify occasionally returns null in
+    /// place of an item it can't load. This means the page can come up short
+    /// of `limit`, even when there are more pages after it.
+    pub fn flatten(self) -> PaginatedResponse<T> {
+        PaginatedResponse {
+            href: self.href,
+            limit: self.limit,
+            offset: self.offset,
+            total: self.total,
+            next: self.next,
+            previous: self.previous,
+            items: self.items.into_iter().flatten().collect(),
         }
     }
 }
 
-/// A parsed and validated Spotify URI. A URI uniquely identifies a Spotify
-/// item, and also includes its type. Note that in this context, "valid" just
-/// means it's not _malformed_. It **doesn't** mean that the URI actually exists
-/// in Spotify.
-#[derive(Clone, Debug, Display, Serialize, Deserialize)]
-#[display(fmt = "spotify:{}:{}", item_type, id)]
-#[serde(try_from = "String", into = "String")]
-pub struct SpotifyUri {
-    item_type: SpotifyItemType,
-    id: String,
-}
-
-impl SpotifyUri {
-    pub fn item_type(&self) -> SpotifyItemType {
-        self.item_type
-    }
-
-    pub fn id(&self) -> &str {
-        &self.id
-    }
-}
-
-// Declare this as a GraphQL scalar
-scalar!(SpotifyUri);
-
-// These two impls needed for serde
-impl From<SpotifyUri> for String {
-    fn from(uri: SpotifyUri) -> Self {
-        uri.to_string()
-    }
-}
-impl TryFrom<String> for SpotifyUri {
-    type Error = <SpotifyUri as FromStr>::Err;
-
-    fn try_from(value: String) -> Result<Self, Self::Error> {
-        value.parse()
-    }
-}
-
-// Needed for the Interface derive on Item
-impl From<&Self> for SpotifyUri {
-    fn from(value: &Self) -> Self {
-        value.clone()
-    }
-}
-
-// For DB interactions
-impl From<&SpotifyUri> for Bson {
-    fn from(uri: &SpotifyUri) -> Self {
-        uri.to_string().into()
-    }
-}
-
-impl FromStr for SpotifyUri {
-    type Err = ParseError;
-
-    fn from_str(value: &str) -> Result<Self, Self::Err> {
-        // Expect URIs of the format "spotify:<type>:<id>"
-        // We have to generate errors as strings first, then map to a proper
-        // error type, cause borrowck
-        let parsed: Result<SpotifyUri, String> =
-            match value.split(':').collect::<Vec<&str>>().as_slice() {
-                ["spotify", item_type, id] => {
-                    // Parse item type. It's possible we get a valid item type
-                    // that we just don't support, just going to treat those
-                    // as invalid for now.
-                    match item_type.parse::<SpotifyItemType>() {
-                        Ok(item_type) => Ok(SpotifyUri {
-                            item_type,
-                            id: (*id).into(),
-                        }),
-                        // Big NG
-                        Err(_) => Err(format!(
-                            "Invalid Spotify URI: unknown item type {}",
-                            item_type
-                        )),
-                    }
-                }
-                _ => Err("Invalid Spotify URI: invalid format".into()),
-            };
-        parsed.map_err(|message| ParseError {
-            message,
-            value: value.into(),
-        })
-    }
-}
-
 /// An item is a polymorphic type that includes anything that can be fetched
 /// from Spotify and tagged in the API.
 #[derive(Clone, Debug, Deserialize, Interface)]
@@ -312,7 +327,7 @@ impl FromStr for SpotifyUri {
     // Rename this field to prevent Relay from thinking it's a node ID (which
     // it will do, even though the type isn't ID)
     field(name = "spotifyId", method = "id", type = "String"),
-    field(name = "uri", type = "SpotifyUri")
+    field(name = "uri", type = "ItemUri")
 )]
 #[serde(tag = "type", rename_all = "snake_case")]
 #[allow(clippy::large_enum_variant)] // don't change external API for micro-opt
@@ -320,16 +335,22 @@ pub enum Item {
     Track(Track),
     Album(AlbumSimplified),
     Artist(Artist),
+    Playlist(Playlist),
+    Show(Show),
+    Episode(Episode),
 }
 
 impl Item {
     /// Get the item's URI. Underscored name is needed to disambiguate from the
     /// equivalent GraphQL resolver on the interface.
-    pub fn uri_(&self) -> &SpotifyUri {
+    pub fn uri_(&self) -> &ItemUri {
         match self {
             Self::Track(track) => &track.uri,
             Self::Album(album) => &album.uri,
             Self::Artist(artist) => &artist.uri,
+            Self::Playlist(playlist) => &playlist.uri,
+            Self::Show(show) => &show.uri,
+            Self::Episode(episode) => &episode.uri,
         }
     }
 }
@@ -4,7 +4,7 @@ module.exports = {
   language: "typescript",
   exclude: ["**/node_modules/**", "**/__generated__/**"],
   customScalars: {
-    SpotifyUri: "string",
+    ItemUri: "string",
     Tag: "string",
   },
 };
@@ -48,7 +48,7 @@ const ItemDetails: React.FC<Props> = ({ taggedItemNodeKey }) => {
 
 export default withQuery<ItemDetailsQuery, Props, "taggedItemNodeKey">({
   query: graphql`
-    query ItemDetailsQuery($uri: SpotifyUri!) {
+    query ItemDetailsQuery($uri: ItemUri!) {
       item(uri: $uri) {
         ...ItemDetails_taggedItemNode
       }